@@ -1,35 +1,243 @@
 use std::ffi::OsStr;
-use std::io::{Seek, SeekFrom};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
 use rusqlite::OptionalExtension;
 use rust_cast::channels::media::MusicTrackMediaMetadata;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use symphonia::core::codecs;
 use symphonia::core::formats::FormatReader;
 use symphonia::core::io::MediaSourceStream;
 use symphonia::core::meta;
 use symphonia::core::meta::MetadataReader as _;
-use symphonia::default::formats::{FlacReader, IsoMp4Reader, MkvReader, MpaReader, OggReader};
+use symphonia::core::units::TimeBase;
+use symphonia::default::formats::{
+    AiffReader, FlacReader, IsoMp4Reader, MkvReader, MpaReader, OggReader, WavReader,
+};
 
-#[derive(Debug)]
+use crate::cache::MetadataCache;
+use crate::cli::Unplayable;
+use crate::cue::{CueSheet, Span};
+use crate::scan::CoverFile;
+use crate::{thumbnail, transcode};
+
+#[derive(Debug, Clone, Default)]
 pub struct Metadata {
     // in rust_cast format
     pub cast_metadata: MusicTrackMediaMetadata,
-    // still in Symphonia format
-    pub visual: Option<meta::Visual>,
+    // the data is read again when served
+    pub visual: Option<Visual>,
+    // every value, cast_metadata only has room for one string
+    pub lists: TagLists,
+    // the CUESHEET tag of single-file rips, see `cue`
+    pub cue_sheet: Option<String>,
+}
+
+/// An embedded picture, without its data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Visual {
+    pub media_type: String,
+    /// A front cover, or a picture with no type
+    pub front: bool,
+    pub sha256: [u8; 32],
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl Visual {
+    fn new(visual: &meta::Visual) -> Self {
+        Self {
+            media_type: visual.media_type.clone(),
+            front: matches!(
+                visual.usage,
+                None | Some(meta::StandardVisualKey::FrontCover)
+            ),
+            sha256: Sha256::digest(&visual.data).into(),
+            dimensions: thumbnail::dimensions(&visual.data),
+        }
+    }
+}
+
+/// Tags that can have several values
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TagLists {
+    pub artists: Vec<String>,
+    pub album_artists: Vec<String>,
+    pub composers: Vec<String>,
+    pub genres: Vec<String>,
+}
+
+impl TagLists {
+    fn push(list: &mut Vec<String>, tag: &meta::Tag) {
+        if let Some(value) = string_value(tag) {
+            // Some files repeat values between frames or comments
+            if !list.contains(&value) {
+                list.push(value);
+            }
+        }
+    }
+}
+
+/// A list from a single-valued source, empty strings count as missing
+fn single_value(value: &Option<String>) -> Vec<String> {
+    value.iter().filter(|v| !v.is_empty()).cloned().collect()
+}
+
+/// Display string for the Cast metadata
+fn join_values(values: &[String]) -> Option<String> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AudioFile {
     pub path: PathBuf,
+    /// What the receiver is told, differs from `container` when transcoding
     pub mime_type: &'static str,
+    pub container: &'static str,
     pub metadata: Option<Metadata>,
+    /// In seconds
+    pub duration: Option<f64>,
+    /// For when there is no embedded art
+    pub cover: Option<CoverFile>,
+    pub format: StreamFormat,
+    /// The receiver can't play this, serve it through `transcode`
+    pub transcode: bool,
+    /// Set on single-file rips, until they are split into tracks
+    pub cue_sheet: Option<CueSheet>,
+    /// For tracks of a cue sheet, the part of the file they play
+    pub span: Option<Span>,
+}
+
+/// The audio stream, as far as the container describes it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamFormat {
+    /// Short name, as Symphonia knows it
+    pub codec: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub bits_per_sample: Option<u32>,
+    pub channels: Option<usize>,
+    /// Why we couldn't transcode the stream, if we couldn't
+    pub transcode_blocker: Option<String>,
+}
+
+/// What a receiver can decode
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    pub max_sample_rate: u32,
+    pub max_bits_per_sample: u32,
+    pub max_channels: usize,
+}
+
+// https://developers.google.com/cast/docs/media
+// Google and Nest speakers share these limits
+pub const CHROMECAST_AUDIO: Capabilities = Capabilities {
+    max_sample_rate: 96_000,
+    max_bits_per_sample: 24,
+    max_channels: 2,
+};
+
+// Multichannel audio goes on to the TV or amplifier over HDMI
+const CHROMECAST_HDMI: Capabilities = Capabilities {
+    max_sample_rate: 96_000,
+    max_bits_per_sample: 24,
+    max_channels: 8,
+};
+
+// TVs with Cast built in and other models we know nothing about:
+// any rate and depth, but only mono and stereo are known to play
+const UNKNOWN_MODEL: Capabilities = Capabilities {
+    max_sample_rate: u32::MAX,
+    max_bits_per_sample: u32::MAX,
+    max_channels: 2,
+};
+
+// By the model name devices advertise (md in the mDNS TXT record)
+// Add models here as their limits become known
+const MODEL_CAPABILITIES: &[(&str, Capabilities)] = &[
+    ("Chromecast Audio", CHROMECAST_AUDIO),
+    ("Google Home", CHROMECAST_AUDIO),
+    ("Google Home Mini", CHROMECAST_AUDIO),
+    ("Google Home Max", CHROMECAST_AUDIO),
+    ("Google Nest Mini", CHROMECAST_AUDIO),
+    ("Google Nest Hub", CHROMECAST_AUDIO),
+    ("Google Nest Hub Max", CHROMECAST_AUDIO),
+    ("Nest Audio", CHROMECAST_AUDIO),
+    // Chromecast with Google TV advertises itself as a plain Chromecast
+    ("Chromecast", CHROMECAST_HDMI),
+    ("Chromecast Ultra", CHROMECAST_HDMI),
+    ("Chromecast HD", CHROMECAST_HDMI),
+    ("Google TV Streamer", CHROMECAST_HDMI),
+];
+
+impl Capabilities {
+    /// Look up a model, unknown models are only limited to stereo
+    ///
+    /// Without a model (discovery skipped), assume a Chromecast Audio.
+    pub fn for_model(model: Option<&str>) -> Self {
+        let Some(model) = model else {
+            return CHROMECAST_AUDIO;
+        };
+        MODEL_CAPABILITIES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(model))
+            .map_or_else(
+                || {
+                    log::warn!("Unknown receiver model {model:?}, only limiting channels");
+                    UNKNOWN_MODEL
+                },
+                |(_, caps)| *caps,
+            )
+    }
+
+    /// Why the receiver can't play a stream with this format, if it can't
+    pub fn check(&self, format: &StreamFormat) -> Option<String> {
+        if let Some(rate) = format
+            .sample_rate
+            .filter(|rate| *rate > self.max_sample_rate)
+        {
+            return Some(format!(
+                "{rate} Hz is over the {} Hz the receiver supports",
+                self.max_sample_rate
+            ));
+        }
+        if let Some(bits) = format
+            .bits_per_sample
+            .filter(|bits| *bits > self.max_bits_per_sample)
+        {
+            return Some(format!(
+                "{bits}-bit samples are over the {} bits the receiver supports",
+                self.max_bits_per_sample
+            ));
+        }
+        if let Some(channels) = format.channels.filter(|chans| *chans > self.max_channels) {
+            return Some(format!(
+                "{channels} channels are over the {} the receiver supports",
+                self.max_channels
+            ));
+        }
+        None
+    }
+
+    /// Divisor of the sample rate that brings it within limits
+    ///
+    /// None if there is no small integer one; we don't resample.
+    pub fn decimation(&self, sample_rate: u32) -> Option<u32> {
+        (1..=8).find(|&n| sample_rate.is_multiple_of(n) && sample_rate / n <= self.max_sample_rate)
+    }
 }
 
 impl AudioFile {
-    pub fn load(path: PathBuf, beets_db: Option<&rusqlite::Connection>) -> anyhow::Result<Self> {
-        if let Some(r) = Self::load_if_supported(path, beets_db)? {
+    pub fn load(
+        path: PathBuf,
+        beets_db: Option<&rusqlite::Connection>,
+        cache: Option<&MetadataCache>,
+    ) -> anyhow::Result<Self> {
+        if let Some(r) = Self::load_if_supported(path, beets_db, cache)? {
             Ok(r)
         } else {
             Err(symphonia::core::errors::Error::Unsupported("Not a known extension").into())
@@ -39,14 +247,29 @@ impl AudioFile {
     /// Load known audio files (based on extension)
     /// Ok(None) if not a known extension
     /// Err if a known extension but parsing failed
+    /// Untagged files are left without metadata, see `scan::Entry::load`
     pub fn load_if_supported(
         path: PathBuf,
         beets_db: Option<&rusqlite::Connection>,
+        cache: Option<&MetadataCache>,
     ) -> anyhow::Result<Option<Self>> {
         let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
         if let Some(ckind) = ContainerKind::from_ext(ext) {
-            let mime_type = ckind.mime_type();
-            let mut metadata = read_metadata(&path, ckind)?;
+            let (mut metadata, probe) = match cache {
+                Some(cache) => read_metadata_cached(&path, ckind, cache)?,
+                None => read_metadata(&path, ckind)?,
+            };
+            let Probe {
+                duration,
+                format,
+                transcode,
+            } = probe;
+            let mime_type = if transcode {
+                log::info!("{} will be transcoded", path.display());
+                transcode::MIME_TYPE
+            } else {
+                ckind.mime_type()
+            };
             if let Some(beets_db) = beets_db {
                 // We still call read_metadata above while discarding
                 // successful results, it validates codecs.
@@ -54,18 +277,100 @@ impl AudioFile {
                 // pick up attached visuals when they aren't in the
                 // beets db.
                 if let Some(beets_meta) = beets_metadata(beets_db, &path)? {
-                    metadata = Some(beets_meta);
+                    // Beets doesn't know about cue sheets
+                    let cue_sheet = metadata.and_then(|meta| meta.cue_sheet);
+                    metadata = Some(Metadata {
+                        cue_sheet,
+                        ..beets_meta
+                    });
                 }
             }
             Ok(Some(Self {
                 path,
                 mime_type,
+                container: ckind.mime_type(),
                 metadata,
+                duration,
+                cover: None,
+                format,
+                transcode,
+                cue_sheet: None,
+                span: None,
             }))
         } else {
             Ok(None)
         }
     }
+
+    /// Make sure the receiver can play this, transcoding if allowed
+    ///
+    /// Returns false if the file should be skipped.
+    pub fn fit(&mut self, caps: &Capabilities, unplayable: Unplayable) -> bool {
+        let Some(reason) = caps.check(&self.format) else {
+            return true;
+        };
+        let path = self.path.display();
+        match unplayable {
+            Unplayable::Skip => {
+                eprintln!("Skipping {path}: {reason}");
+                false
+            }
+            Unplayable::Transcode => {
+                if let Some(ref blocker) = self.format.transcode_blocker {
+                    eprintln!("Skipping {path}: {reason}, and it can't be transcoded: {blocker}");
+                    return false;
+                }
+                if let Some(rate) = self.format.sample_rate {
+                    if caps.decimation(rate).is_none() {
+                        eprintln!("Skipping {path}: {reason}, and it can't be downsampled");
+                        return false;
+                    }
+                }
+                eprintln!("Transcoding {path}: {reason}");
+                self.transcode = true;
+                self.mime_type = transcode::MIME_TYPE;
+                true
+            }
+        }
+    }
+}
+
+/// Whether a file looks like one we can load, going by its extension
+pub fn has_known_extension(path: &Path) -> bool {
+    let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
+    ContainerKind::from_ext(ext).is_some()
+}
+
+/// The data of an embedded picture, found by its hash
+///
+/// Lets us drop pictures between requests.
+pub fn embedded_visual(path: &Path, sha256: &[u8; 32]) -> anyhow::Result<Option<Box<[u8]>>> {
+    let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
+    let Some(ckind) = ContainerKind::from_ext(ext) else {
+        anyhow::bail!("Not a known extension");
+    };
+    let (tags, _) = read_tags(path, ckind)?;
+    Ok(tags.and_then(|tags| {
+        tags.visuals()
+            .iter()
+            .find(|visual| Sha256::digest(&visual.data)[..] == sha256[..])
+            .map(|visual| visual.data.clone())
+    }))
+}
+
+/// MIME types of the files we can load
+pub fn supported_mime_types() -> impl Iterator<Item = &'static str> {
+    ContainerKind::ALL.into_iter().map(ContainerKind::mime_type)
+}
+
+/// Open a file for decoding, picking the container from the extension
+pub fn open_format(path: &Path) -> anyhow::Result<Box<dyn FormatReader>> {
+    let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
+    let Some(ckind) = ContainerKind::from_ext(ext) else {
+        anyhow::bail!("Not a known extension");
+    };
+    let src = std::fs::File::open(path)?;
+    ckind.open(MediaSourceStream::new(Box::new(src), Default::default()))
 }
 
 fn string_value(tag: &meta::Tag) -> Option<String> {
@@ -92,28 +397,66 @@ fn u32_value(tag: &meta::Tag) -> Option<u32> {
 fn convert_metadata(meta: &meta::MetadataRevision) -> Metadata {
     use symphonia::core::meta::StandardTagKey::*;
     let mut cmeta = MusicTrackMediaMetadata::default();
-    // XXX for multi-valued tags, last one will win
+    let mut lists = TagLists::default();
+    let mut cue_sheet = None;
+    // For other single-valued tags, last one will win
     for tag in meta.tags() {
+        // Vorbis comment, as written by CUETools and foobar2000
+        if tag.key.eq_ignore_ascii_case("CUESHEET") {
+            cue_sheet = string_value(tag);
+            continue;
+        }
         let Some(stdtag) = tag.std_key else { continue };
         match stdtag {
             Album => cmeta.album_name = string_value(tag),
             TrackTitle => cmeta.title = string_value(tag),
-            AlbumArtist => cmeta.album_artist = string_value(tag),
-            Artist => cmeta.artist = string_value(tag),
-            Composer => cmeta.composer = string_value(tag),
+            AlbumArtist => TagLists::push(&mut lists.album_artists, tag),
+            Artist => TagLists::push(&mut lists.artists, tag),
+            Composer => TagLists::push(&mut lists.composers, tag),
+            Genre => TagLists::push(&mut lists.genres, tag),
             TrackNumber => cmeta.track_number = u32_value(tag),
             DiscNumber => cmeta.disc_number = u32_value(tag),
             ReleaseDate => cmeta.release_date = string_value(tag),
             _ => (),
         }
     }
+    cmeta.album_artist = join_values(&lists.album_artists);
+    cmeta.artist = join_values(&lists.artists);
+    cmeta.composer = join_values(&lists.composers);
 
-    // First seems good enough, ordering would require experimentation
-    let visual = meta.visuals().first().cloned();
+    let visual = meta
+        .visuals()
+        .iter()
+        .max_by_key(|v| visual_rank(v))
+        .map(Visual::new);
 
     Metadata {
         cast_metadata: cmeta,
         visual,
+        lists,
+        cue_sheet,
+    }
+}
+
+/// Rank embedded pictures: front covers first, then untyped pictures
+/// (MP4 has no picture types), then the rest (back covers, artist
+/// photos, icons); larger is better within each group
+fn visual_rank(visual: &meta::Visual) -> impl Ord {
+    let usage = match visual.usage {
+        Some(meta::StandardVisualKey::FrontCover) => 2,
+        None => 1,
+        Some(_) => 0,
+    };
+    let area = visual
+        .dimensions
+        .map_or(0, |size| u64::from(size.width) * u64::from(size.height));
+    (usage, area, visual.data.len())
+}
+
+impl Metadata {
+    /// Whether the embedded picture should beat a cover file
+    pub fn has_front_visual(&self) -> bool {
+        self.visual.as_ref().is_some_and(|visual| visual.front)
     }
 }
 
@@ -124,9 +467,22 @@ enum ContainerKind {
     Matroska,
     Mp3,
     Mp4,
+    Wav,
+    // Transcoded
+    Aiff,
 }
 
 impl ContainerKind {
+    const ALL: [Self; 7] = [
+        Self::Flac,
+        Self::Ogg,
+        Self::Matroska,
+        Self::Mp3,
+        Self::Mp4,
+        Self::Wav,
+        Self::Aiff,
+    ];
+
     fn from_ext(ext: &str) -> Option<Self> {
         match &*ext.to_ascii_lowercase() {
             "flac" => Some(Self::Flac),
@@ -134,9 +490,11 @@ impl ContainerKind {
             "mka" => Some(Self::Matroska),
             "mp3" => Some(Self::Mp3),
             // mp4 metadata for aac? meh
-            // Also the m4a extension is shared with ALAC, a pointless format the Chromecast won't handle
+            // Also the m4a extension is shared with ALAC, which gets transcoded
             "m4a" => Some(Self::Mp4),
-            // wav? only if metadata can be made to work
+            "wav" => Some(Self::Wav),
+            "aif" | "aiff" => Some(Self::Aiff),
+            // No WavPack decoder in symphonia
             _ => None,
         }
     }
@@ -148,18 +506,168 @@ impl ContainerKind {
             Self::Matroska => "audio/webm",
             Self::Mp3 => "audio/mpeg",
             Self::Mp4 => "audio/m4a",
+            Self::Wav => "audio/wav",
+            Self::Aiff => "audio/aiff",
+        }
+    }
+
+    // Don't use the probe system, which currently ignores the extension hint
+    // build a reader directly
+    fn open(self, mss: MediaSourceStream) -> anyhow::Result<Box<dyn FormatReader>> {
+        let opts = &Default::default();
+        Ok(match self {
+            Self::Flac => Box::new(FlacReader::try_new(mss, opts)?),
+            Self::Ogg => Box::new(OggReader::try_new(mss, opts)?),
+            Self::Matroska => Box::new(MkvReader::try_new(mss, opts)?),
+            Self::Mp3 => Box::new(MpaReader::try_new(mss, opts)?),
+            Self::Mp4 => Box::new(IsoMp4Reader::try_new(mss, opts)?),
+            Self::Wav => Box::new(WavReader::try_new(mss, opts)?),
+            Self::Aiff => Box::new(AiffReader::try_new(mss, opts)?),
+        })
+    }
+}
+
+/// What `read_metadata` learns about the audio stream
+#[derive(Default)]
+struct Probe {
+    /// In seconds, when the container makes it known
+    duration: Option<f64>,
+    format: StreamFormat,
+    transcode: bool,
+}
+
+impl Probe {
+    fn new(reader: &dyn FormatReader, transcode: bool) -> Self {
+        let format = reader
+            .tracks()
+            .first()
+            .map(|track| StreamFormat {
+                codec: symphonia::default::get_codecs()
+                    .get_codec(track.codec_params.codec)
+                    .map(|desc| desc.short_name.to_owned()),
+                sample_rate: track.codec_params.sample_rate,
+                bits_per_sample: track.codec_params.bits_per_sample,
+                channels: track.codec_params.channels.map(|chans| chans.count()),
+                transcode_blocker: transcode_blocker(&track.codec_params).map(str::to_owned),
+            })
+            .unwrap_or_default();
+        Self {
+            duration: track_duration(reader),
+            format,
+            transcode,
+        }
+    }
+}
+
+/// Read tags and validate the stream
+fn read_metadata(
+    path: &Path,
+    container_kind: ContainerKind,
+) -> anyhow::Result<(Option<Metadata>, Probe)> {
+    let (tags, probe) = read_tags(path, container_kind)?;
+    Ok((tags.as_ref().map(convert_metadata), probe))
+}
+
+/// `read_metadata`, going through the cache
+///
+/// Failures aren't cached, they may not last.
+fn read_metadata_cached(
+    path: &Path,
+    container_kind: ContainerKind,
+    cache: &MetadataCache,
+) -> anyhow::Result<(Option<Metadata>, Probe)> {
+    let stat = std::fs::metadata(path)?;
+    match cache.get(path, &stat) {
+        Ok(Some(data)) => match serde_json::from_str::<Cached>(&data) {
+            Ok(cached) => return Ok(cached.into_parts()),
+            Err(err) => log::warn!("{}: bad cache entry: {err}", path.display()),
+        },
+        Ok(None) => (),
+        Err(err) => log::warn!("{}: cache lookup failed: {err:#}", path.display()),
+    }
+    let (metadata, probe) = read_metadata(path, container_kind)?;
+    let data = serde_json::to_string(&Cached::new(metadata.as_ref(), &probe))?;
+    if let Err(err) = cache.put(path, &stat, &data) {
+        log::warn!("{}: not cached: {err:#}", path.display());
+    }
+    Ok((metadata, probe))
+}
+
+/// What the metadata cache keeps of `read_metadata` results
+///
+/// Album artists, artists and composers are joined again from `lists`.
+#[derive(Serialize, Deserialize)]
+struct Cached {
+    title: Option<String>,
+    album: Option<String>,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    release_date: Option<String>,
+    lists: TagLists,
+    visual: Option<Visual>,
+    cue_sheet: Option<String>,
+    // Whether there were tags at all
+    tagged: bool,
+    duration: Option<f64>,
+    format: StreamFormat,
+    transcode: bool,
+}
+
+impl Cached {
+    fn new(metadata: Option<&Metadata>, probe: &Probe) -> Self {
+        let cmeta = metadata.map(|meta| &meta.cast_metadata);
+        Self {
+            title: cmeta.and_then(|cmeta| cmeta.title.clone()),
+            album: cmeta.and_then(|cmeta| cmeta.album_name.clone()),
+            track_number: cmeta.and_then(|cmeta| cmeta.track_number),
+            disc_number: cmeta.and_then(|cmeta| cmeta.disc_number),
+            release_date: cmeta.and_then(|cmeta| cmeta.release_date.clone()),
+            lists: metadata.map(|meta| meta.lists.clone()).unwrap_or_default(),
+            visual: metadata.and_then(|meta| meta.visual.clone()),
+            cue_sheet: metadata.and_then(|meta| meta.cue_sheet.clone()),
+            tagged: metadata.is_some(),
+            duration: probe.duration,
+            format: probe.format.clone(),
+            transcode: probe.transcode,
         }
     }
+
+    fn into_parts(self) -> (Option<Metadata>, Probe) {
+        let metadata = self.tagged.then(|| Metadata {
+            cast_metadata: MusicTrackMediaMetadata {
+                title: self.title,
+                album_name: self.album,
+                album_artist: join_values(&self.lists.album_artists),
+                artist: join_values(&self.lists.artists),
+                composer: join_values(&self.lists.composers),
+                track_number: self.track_number,
+                disc_number: self.disc_number,
+                release_date: self.release_date,
+                images: Vec::new(),
+            },
+            visual: self.visual,
+            lists: self.lists,
+            cue_sheet: self.cue_sheet,
+        });
+        let probe = Probe {
+            duration: self.duration,
+            format: self.format,
+            transcode: self.transcode,
+        };
+        (metadata, probe)
+    }
 }
 
-fn read_metadata(path: &Path, container_kind: ContainerKind) -> anyhow::Result<Option<Metadata>> {
+/// Read tags in Symphonia format and validate the stream
+fn read_tags(
+    path: &Path,
+    container_kind: ContainerKind,
+) -> anyhow::Result<(Option<meta::MetadataRevision>, Probe)> {
     let src = std::fs::File::open(path)?;
     // Default options for buffering
     let mut mss = MediaSourceStream::new(Box::new(src), Default::default());
 
-    // Don't use the probe system, which currently ignores the extension hint
-    // build a reader directly
-    let mut reader: Box<dyn FormatReader> = match container_kind {
+    let mut reader = match container_kind {
         // For Mp3 metadata we just require id3v2, which is a container
         // around the mp3 file.  id3v1 would be 128 bytes tacked on after
         // the mp3 frames and immediately before EOF, can't really be
@@ -167,7 +675,18 @@ fn read_metadata(path: &Path, container_kind: ContainerKind) -> anyhow::Result<O
         ContainerKind::Mp3 => {
             let mut mreader = symphonia_metadata::id3v2::Id3v2Reader::new(&Default::default());
             match mreader.read_all(&mut mss) {
-                Ok(meta) => return Ok(Some(convert_metadata(&meta))),
+                Ok(meta) => {
+                    // MpaReader picks up Xing/VBRI headers for VBR files,
+                    // and estimates from the bitrate otherwise
+                    let probe = match MpaReader::try_new(mss, &Default::default()) {
+                        Ok(reader) => Probe::new(&reader, false),
+                        Err(err) => {
+                            log::warn!("{}: no duration: {err}", path.display());
+                            Probe::default()
+                        }
+                    };
+                    return Ok((Some(meta), probe));
+                }
                 Err(err) => {
                     if !matches!(err, symphonia::core::errors::Error::Unsupported(_)) {
                         return Err(err.into());
@@ -177,26 +696,136 @@ fn read_metadata(path: &Path, container_kind: ContainerKind) -> anyhow::Result<O
             log::warn!("{} does not start with ID3v2 frames", path.display());
             // This just validates this is an MPEG stream
             let reader = MpaReader::try_new(mss, &Default::default())?;
+            let probe = Probe::new(&reader, false);
             let mut mss = Box::new(reader).into_inner();
             mss.seek(SeekFrom::End(-128))?;
             let mut meta = meta::MetadataBuilder::new();
             symphonia_metadata::id3v1::read_id3v1(&mut mss, &mut meta)?;
-            return Ok(Some(convert_metadata(&meta.metadata())));
+            return Ok((Some(meta.metadata()), probe));
         }
-        ContainerKind::Flac => Box::new(FlacReader::try_new(mss, &Default::default())?),
-        ContainerKind::Ogg => Box::new(OggReader::try_new(mss, &Default::default())?),
-        ContainerKind::Matroska => Box::new(MkvReader::try_new(mss, &Default::default())?),
-        ContainerKind::Mp4 => Box::new(IsoMp4Reader::try_new(mss, &Default::default())?),
+        _ => container_kind.open(mss)?,
     };
 
-    validate_codecs(&*reader, container_kind)?;
+    let transcode = validate_codecs(&*reader, container_kind)?;
+    let probe = Probe::new(&*reader, transcode);
+
+    // Symphonia reads LIST/INFO chunks, but id3 chunks carry more
+    // (and cover art) when taggers added them
+    if container_kind == ContainerKind::Wav {
+        match wav_id3_tags(path) {
+            Ok(Some(meta)) => return Ok((Some(meta), probe)),
+            Ok(None) => (),
+            Err(err) => log::warn!("{}: unreadable id3 chunk: {err}", path.display()),
+        }
+    }
 
     let meta = reader.metadata();
-    let Some(meta) = meta.current() else {
+    Ok((meta.current().cloned(), probe))
+}
+
+/// Read tags from the id3 chunk of a RIFF/WAVE file
+fn wav_id3_tags(path: &Path) -> anyhow::Result<Option<meta::MetadataRevision>> {
+    let file = BufReader::new(std::fs::File::open(path)?);
+    let Some(chunk) = riff_chunk(file, b"id3 ")? else {
         return Ok(None);
     };
+    let mut mss = MediaSourceStream::new(Box::new(Cursor::new(chunk)), Default::default());
+    let mut mreader = symphonia_metadata::id3v2::Id3v2Reader::new(&Default::default());
+    Ok(Some(mreader.read_all(&mut mss)?))
+}
+
+/// Find a top-level chunk of a RIFF/WAVE file, return its contents
+fn riff_chunk(mut file: impl Read + Seek, id: &[u8; 4]) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut header = [0; 12];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        anyhow::bail!("Not a RIFF/WAVE file");
+    }
+    loop {
+        let mut chunk_header = [0; 8];
+        match file.read_exact(&mut chunk_header) {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let len = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap());
+        // Some taggers write ID3 in uppercase
+        if chunk_header[0..4].eq_ignore_ascii_case(id) {
+            // The length comes from the file, don't allocate it upfront
+            let mut data = Vec::new();
+            file.take(len.into()).read_to_end(&mut data)?;
+            if data.len() != usize::try_from(len)? {
+                anyhow::bail!("Truncated RIFF chunk");
+            }
+            return Ok(Some(data));
+        }
+        // Chunks are padded to an even length
+        file.seek(SeekFrom::Current(i64::from(len) + i64::from(len % 2)))?;
+    }
+}
 
-    Ok(Some(convert_metadata(meta)))
+/// Metadata guessed from file and directory names, for untagged files
+///
+/// Expects the usual "Album/03 - Title.ext" layout.
+pub fn filename_metadata(path: &Path) -> Option<Metadata> {
+    let stem = path.file_stem()?.to_str()?;
+    let (track_number, title) = split_track_number(stem);
+    let album_name = path
+        .parent()
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str)
+        .map(str::to_owned);
+    Some(Metadata {
+        cast_metadata: MusicTrackMediaMetadata {
+            title: Some(title.to_owned()),
+            track_number,
+            album_name,
+            ..Default::default()
+        },
+        visual: None,
+        lists: TagLists::default(),
+        cue_sheet: None,
+    })
+}
+
+/// Split a leading track number off a file stem, as in "03 - Title"
+fn split_track_number(stem: &str) -> (Option<u32>, &str) {
+    let rest = stem.trim_start_matches(|c: char| c.is_ascii_digit());
+    let digits = stem.len() - rest.len();
+    // Longer is more likely a year, or part of the title
+    if digits == 0 || digits > 3 {
+        return (None, stem);
+    }
+    let title = rest.trim_start_matches([' ', '-', '.', '_']);
+    if title.is_empty() {
+        return (None, stem);
+    }
+    (stem[..digits].parse().ok(), title)
+}
+
+/// Duration in seconds, from the codec parameters of the first track
+fn track_duration(reader: &dyn FormatReader) -> Option<f64> {
+    let params = &reader.tracks().first()?.codec_params;
+    let n_frames = params.n_frames?;
+    let time_base = match params.time_base {
+        Some(tb) => tb,
+        None => TimeBase::new(1, params.sample_rate?),
+    };
+    let time = time_base.calc_time(n_frames);
+    // No lossless conversion from u64
+    Some(time.seconds as f64 + time.frac)
+}
+
+/// Open the beets library, read-only
+pub fn open_beets_db(path: Option<&Path>) -> anyhow::Result<Option<rusqlite::Connection>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    use rusqlite::OpenFlags;
+    Ok(Some(rusqlite::Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_EXRESCODE,
+    )?))
 }
 
 fn beets_metadata(
@@ -205,7 +834,7 @@ fn beets_metadata(
 ) -> anyhow::Result<Option<Metadata>> {
     let mut stmt = beets_db.prepare_cached(
         "SELECT album, title, albumartist, artist, composer, \
-        track, disc, year, month, day \
+        track, disc, year, month, day, genre \
         FROM items WHERE path = ?1",
     )?;
     Ok(stmt
@@ -220,44 +849,139 @@ fn beets_metadata(
             // Assuming beets has fetchart enabled with default settings,
             // we don't need to do anything for images,
             // they will be in cover.jpg which we autodetect.
+            let album_artist: Option<String> = row.get_unwrap(2);
+            let artist: Option<String> = row.get_unwrap(3);
+            let composer: Option<String> = row.get_unwrap(4);
+            let genre: Option<String> = row.get_unwrap(10);
+            // beets keeps one string per tag
+            let lists = TagLists {
+                artists: single_value(&artist),
+                album_artists: single_value(&album_artist),
+                composers: single_value(&composer),
+                genres: single_value(&genre),
+            };
             Ok(Metadata {
                 cast_metadata: MusicTrackMediaMetadata {
                     album_name: row.get_unwrap(0),
                     title: row.get_unwrap(1),
-                    album_artist: row.get_unwrap(2),
-                    artist: row.get_unwrap(3),
-                    composer: row.get_unwrap(4),
+                    album_artist,
+                    artist,
+                    composer,
                     track_number: row.get_unwrap(5),
                     disc_number: row.get_unwrap(6),
                     release_date,
                     images: Vec::new(),
                 },
                 visual: None,
+                lists,
+                cue_sheet: None,
             })
         })
         .optional()?)
 }
 
+/// Check the receiver can play the stream, or that we can transcode it
+///
+/// Returns true if the stream needs transcoding.
 // https://developer.mozilla.org/en-US/docs/Web/Media/Formats/codecs_parameter
-fn validate_codecs(reader: &dyn FormatReader, container_kind: ContainerKind) -> anyhow::Result<()> {
+fn validate_codecs(
+    reader: &dyn FormatReader,
+    container_kind: ContainerKind,
+) -> anyhow::Result<bool> {
+    let mut transcode = false;
     for track in reader.tracks() {
         let codec = track.codec_params.codec;
         log::debug!("track {:?} codec {:x?}", track, codec);
-        if match container_kind {
-            ContainerKind::Flac => codec != codecs::CODEC_TYPE_FLAC,
+        let native = match container_kind {
+            ContainerKind::Flac => codec == codecs::CODEC_TYPE_FLAC,
             // If the extension is opus, we might want to be stricter
             ContainerKind::Ogg | ContainerKind::Matroska => {
-                codec != codecs::CODEC_TYPE_VORBIS && codec != codecs::CODEC_TYPE_OPUS
+                codec == codecs::CODEC_TYPE_VORBIS || codec == codecs::CODEC_TYPE_OPUS
             }
-            ContainerKind::Mp3 => codec != codecs::CODEC_TYPE_MP3,
-            ContainerKind::Mp4 => codec != codecs::CODEC_TYPE_AAC,
-        } {
+            ContainerKind::Mp3 => codec == codecs::CODEC_TYPE_MP3,
+            ContainerKind::Mp4 => codec == codecs::CODEC_TYPE_AAC,
+            // Integer PCM only, float gets transcoded
+            ContainerKind::Wav => [
+                codecs::CODEC_TYPE_PCM_U8,
+                codecs::CODEC_TYPE_PCM_S16LE,
+                codecs::CODEC_TYPE_PCM_S24LE,
+            ]
+            .contains(&codec),
+            ContainerKind::Aiff => false,
+        };
+        if native {
+            continue;
+        }
+        if let Some(blocker) = transcode_blocker(&track.codec_params) {
             anyhow::bail!(
-                "Unexpected codec {:04x?} for container {}",
+                "Codec {:04x?} in {} needs transcoding, but {blocker}",
                 codec,
                 container_kind.mime_type()
             )
         }
+        transcode = true;
     }
-    Ok(())
+    Ok(transcode)
+}
+
+/// Why `transcode::WavStream` can't decode a track, if it can't
+fn transcode_blocker(params: &codecs::CodecParameters) -> Option<&'static str> {
+    if symphonia::default::get_codecs()
+        .get_codec(params.codec)
+        .is_none()
+    {
+        Some("there is no decoder for it")
+    } else if params.n_frames.is_none() {
+        // The WAV header needs the length upfront
+        Some("its length is unknown")
+    } else if params.sample_rate.is_none() || params.channels.is_none() {
+        Some("its sample rate or channels are unknown")
+    } else {
+        None
+    }
+}
+
+#[test]
+fn check_split_track_number() {
+    assert_eq!(split_track_number("03 - Title"), (Some(3), "Title"));
+    assert_eq!(split_track_number("12. Title"), (Some(12), "Title"));
+    assert_eq!(split_track_number("1_Title"), (Some(1), "Title"));
+    assert_eq!(split_track_number("Title"), (None, "Title"));
+    assert_eq!(split_track_number("1999"), (None, "1999"));
+    assert_eq!(split_track_number("42"), (None, "42"));
+}
+
+#[test]
+fn check_multi_valued_tags() {
+    use meta::{StandardTagKey, Tag, Value};
+    let mut builder = meta::MetadataBuilder::new();
+    for artist in ["A", "B", "A"] {
+        builder.add_tag(Tag::new(
+            Some(StandardTagKey::Artist),
+            "ARTIST",
+            Value::from(artist),
+        ));
+    }
+    builder.add_tag(Tag::new(
+        Some(StandardTagKey::Genre),
+        "GENRE",
+        Value::from("Jazz"),
+    ));
+    let meta = convert_metadata(&builder.metadata());
+    assert_eq!(meta.lists.artists, ["A", "B"]);
+    assert_eq!(meta.cast_metadata.artist.as_deref(), Some("A, B"));
+    assert_eq!(meta.lists.genres, ["Jazz"]);
+}
+
+#[test]
+fn check_riff_chunk() {
+    let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+    wav.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+    wav.extend_from_slice(b"ID3 \x02\0\0\0id");
+    let chunk = riff_chunk(Cursor::new(&wav), b"id3 ").unwrap();
+    assert_eq!(chunk.as_deref(), Some(&b"id"[..]));
+    assert!(riff_chunk(Cursor::new(&wav), b"data").unwrap().is_none());
+    // A length past the end of the file
+    wav.extend_from_slice(b"data\xff\xff\xff\xff");
+    assert!(riff_chunk(Cursor::new(&wav), b"data").is_err());
 }
@@ -0,0 +1,76 @@
+//! Tags and stream parameters of files we already read, kept between runs
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use rusqlite::OptionalExtension;
+
+// Bump when what we store changes, the cache is emptied then
+const SCHEMA_VERSION: i32 = 3;
+
+// Loader threads each have a connection, and wait on each other's writes
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// $XDG_CACHE_HOME/joujou
+pub fn cache_dir() -> anyhow::Result<PathBuf> {
+    crate::resume::xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+/// Entries are keyed by path, and only valid for the same mtime and size
+pub struct MetadataCache {
+    conn: rusqlite::Connection,
+}
+
+impl MetadataCache {
+    pub fn open() -> anyhow::Result<Self> {
+        let dir = cache_dir()?;
+        std::fs::create_dir_all(&dir)?;
+        let conn = rusqlite::Connection::open(dir.join("metadata.sqlite"))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // Readers don't block the writer
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            conn.execute_batch(&format!(
+                "BEGIN IMMEDIATE; \
+                DROP TABLE IF EXISTS files; \
+                CREATE TABLE files (path BLOB PRIMARY KEY, mtime INTEGER NOT NULL, \
+                size INTEGER NOT NULL, data TEXT NOT NULL); \
+                PRAGMA user_version = {SCHEMA_VERSION}; \
+                COMMIT;"
+            ))?;
+        }
+        Ok(Self { conn })
+    }
+
+    /// The data stored for a file, unless it changed since
+    pub fn get(&self, path: &Path, stat: &std::fs::Metadata) -> anyhow::Result<Option<String>> {
+        let (path, mtime, size) = key(path, stat)?;
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT data FROM files WHERE path = ?1 AND mtime = ?2 AND size = ?3",
+        )?;
+        Ok(stmt
+            .query_row((path.as_os_str().as_bytes(), mtime, size), |row| row.get(0))
+            .optional()?)
+    }
+
+    pub fn put(&self, path: &Path, stat: &std::fs::Metadata, data: &str) -> anyhow::Result<()> {
+        let (path, mtime, size) = key(path, stat)?;
+        let mut stmt = self.conn.prepare_cached(
+            "INSERT OR REPLACE INTO files (path, mtime, size, data) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        stmt.execute((path.as_os_str().as_bytes(), mtime, size, data))?;
+        Ok(())
+    }
+}
+
+/// Paths are made absolute, relative ones depend on where we run
+fn key(path: &Path, stat: &std::fs::Metadata) -> anyhow::Result<(PathBuf, i64, i64)> {
+    let mtime = stat.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+    Ok((
+        std::path::absolute(path)?,
+        mtime.try_into()?,
+        stat.len().try_into()?,
+    ))
+}
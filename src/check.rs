@@ -0,0 +1,193 @@
+//! Dry run: report what would be cast, without touching the network
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::audio::{self, AudioFile, Capabilities};
+use crate::cli::Sort;
+use crate::scan;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+enum Cover {
+    Embedded { mime_type: String },
+    File { path: PathBuf },
+}
+
+/// The tags sent to the receiver
+#[derive(Debug, Serialize)]
+struct Tags {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    composer: Option<String>,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    release_date: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct FileReport {
+    path: PathBuf,
+    container: Option<&'static str>,
+    codec: Option<String>,
+    sample_rate: Option<u32>,
+    bits_per_sample: Option<u32>,
+    channels: Option<usize>,
+    /// In seconds
+    duration: Option<f64>,
+    /// As served, after transcoding
+    mime_type: Option<&'static str>,
+    transcode: bool,
+    /// Why a Chromecast Audio can't play this as-is
+    over_limits: Option<String>,
+    tags: Option<Tags>,
+    cover: Option<Cover>,
+    error: Option<String>,
+}
+
+impl FileReport {
+    fn new(ent: &AudioFile, caps: &Capabilities) -> Self {
+        let meta = ent.metadata.as_ref();
+        let tags = meta.map(|meta| {
+            let cmeta = &meta.cast_metadata;
+            Tags {
+                title: cmeta.title.clone(),
+                artist: cmeta.artist.clone(),
+                album: cmeta.album_name.clone(),
+                album_artist: cmeta.album_artist.clone(),
+                composer: cmeta.composer.clone(),
+                track_number: cmeta.track_number,
+                disc_number: cmeta.disc_number,
+                release_date: cmeta.release_date.clone(),
+            }
+        });
+        // Same precedence as http::make_app
+        let use_visual = meta.is_some_and(|meta| meta.has_front_visual()) || ent.cover.is_none();
+        let cover = if let Some(visual) = meta
+            .and_then(|meta| meta.visual.as_ref())
+            .filter(|_| use_visual)
+        {
+            Some(Cover::Embedded {
+                mime_type: visual.media_type.clone(),
+            })
+        } else {
+            ent.cover.as_ref().map(|cover| Cover::File {
+                path: cover.path.clone(),
+            })
+        };
+        Self {
+            path: ent.path.clone(),
+            container: Some(ent.container),
+            codec: ent.format.codec.clone(),
+            sample_rate: ent.format.sample_rate,
+            bits_per_sample: ent.format.bits_per_sample,
+            channels: ent.format.channels,
+            duration: ent.duration,
+            mime_type: Some(ent.mime_type),
+            transcode: ent.transcode,
+            over_limits: caps.check(&ent.format),
+            tags,
+            cover,
+            error: None,
+        }
+    }
+
+    fn print(&self) {
+        println!("{}", self.path.display());
+        if let Some(ref error) = self.error {
+            println!("  error: {error}");
+            return;
+        }
+        let mut format = Vec::new();
+        format.extend(self.container.map(str::to_owned));
+        format.extend(self.codec.clone());
+        format.extend(self.sample_rate.map(|rate| format!("{rate} Hz")));
+        format.extend(self.bits_per_sample.map(|bits| format!("{bits}-bit")));
+        format.extend(self.channels.map(|chans| format!("{chans} channels")));
+        if let Some(duration) = self.duration {
+            let secs = duration.round() as u64;
+            format.push(format!("{}:{:02}", secs / 60, secs % 60));
+        }
+        println!("  format: {}", format.join(", "));
+        if self.transcode {
+            println!("  transcoded to {}", self.mime_type.unwrap_or("?"));
+        }
+        if let Some(ref reason) = self.over_limits {
+            println!("  over limits: {reason}");
+        }
+        if let Some(ref tags) = self.tags {
+            let fields = [
+                ("title", tags.title.clone()),
+                ("artist", tags.artist.clone()),
+                ("album", tags.album.clone()),
+                ("album artist", tags.album_artist.clone()),
+                ("composer", tags.composer.clone()),
+                ("track", tags.track_number.map(|n| n.to_string())),
+                ("disc", tags.disc_number.map(|n| n.to_string())),
+                ("date", tags.release_date.clone()),
+            ];
+            for (name, value) in fields {
+                if let Some(value) = value {
+                    println!("  {name}: {value}");
+                }
+            }
+        } else {
+            println!("  no tags");
+        }
+        match self.cover {
+            Some(Cover::Embedded { ref mime_type }) => println!("  cover: embedded {mime_type}"),
+            Some(Cover::File { ref path }) => println!("  cover: {}", path.display()),
+            None => println!("  no cover"),
+        }
+    }
+}
+
+/// Scan like `play` would, report on every file
+///
+/// Fails if any file couldn't be loaded, so that it can gate scripts.
+pub fn check<P: AsRef<Path>>(
+    paths: &[P],
+    beets_db: Option<&Path>,
+    sort: Sort,
+    json: bool,
+) -> anyhow::Result<()> {
+    let playlist = scan::paths_to_playlist(paths, sort, beets_db)?;
+    // Without a device, check against the most limited receivers
+    let caps = audio::CHROMECAST_AUDIO;
+    let mut failures = 0;
+    // Unlike play, this loads every file upfront
+    let loaded = scan::load_entries(&playlist.entries, beets_db)?;
+    let reports: Vec<FileReport> = playlist
+        .entries
+        .iter()
+        .zip(loaded)
+        .map(|(listed, loaded)| match loaded {
+            Ok(ent) => FileReport::new(&ent, &caps),
+            Err(error) => {
+                failures += 1;
+                FileReport {
+                    path: listed.path.clone(),
+                    error: Some(format!("{error:#}")),
+                    ..Default::default()
+                }
+            }
+        })
+        .collect();
+    if reports.is_empty() {
+        eprintln!("No audio files found");
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    } else {
+        for report in reports.iter() {
+            report.print();
+        }
+    }
+    if failures > 0 {
+        anyhow::bail!("{failures} of {} files could not be loaded", reports.len());
+    }
+    Ok(())
+}
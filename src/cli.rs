@@ -5,12 +5,15 @@
 // https://github.com/rosetta-rs/argparse-rosetta-rs
 
 use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
 use std::num::{NonZeroU16, ParseIntError};
 use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use bpaf::{construct, OptionParser, Parser};
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
 pub enum Command {
@@ -18,7 +21,15 @@ pub enum Command {
         paths: Vec<PathBuf>,
         playlist_start: NonZeroU16,
     },
+    Resume,
     Listen,
+    Devices {
+        json: bool,
+    },
+    Check {
+        paths: Vec<PathBuf>,
+        json: bool,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -82,10 +93,123 @@ impl FromStr for PortOrRange {
     }
 }
 
+/// What to do with files the receiver can't play as they are
+#[derive(Debug, Clone, Copy)]
+pub enum Unplayable {
+    Skip,
+    Transcode,
+}
+
+impl FromStr for Unplayable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "transcode" => Ok(Self::Transcode),
+            _ => Err(format!("Expected skip or transcode, got {s}")),
+        }
+    }
+}
+
+/// Order of the files within each directory
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    /// Natural order of the paths
+    Path,
+    /// Album artist, date, album, disc, track
+    Tags,
+    /// Oldest first
+    Mtime,
+    /// As the file system lists them
+    None,
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(Self::Path),
+            "tags" => Ok(Self::Tags),
+            "mtime" => Ok(Self::Mtime),
+            "none" => Ok(Self::None),
+            _ => Err(format!("Expected path, tags, mtime or none, got {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shuffle {
+    Tracks,
+    /// Tracks stay in order within each album
+    Albums,
+}
+
+const DEFAULT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The port Cast devices listen on
+const DEFAULT_CAST_PORT: u16 = 8009;
+
+/// A Chromecast address given on the command line
+#[derive(Debug, Clone)]
+pub struct CastHost {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for CastHost {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // [v6]:port or v4:port
+        if let Ok(sa) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                host: sa.ip().to_string(),
+                port: sa.port(),
+            });
+        }
+        // Bare IPv6 addresses contain colons too
+        if s.parse::<IpAddr>().is_ok() {
+            return Ok(Self {
+                host: s.to_owned(),
+                port: DEFAULT_CAST_PORT,
+            });
+        }
+        match s.split_once(':') {
+            Some((host, port)) => Ok(Self {
+                host: host.to_owned(),
+                port: port.parse()?,
+            }),
+            None => Ok(Self {
+                host: s.to_owned(),
+                port: DEFAULT_CAST_PORT,
+            }),
+        }
+    }
+}
+
+/// How to find the Chromecast
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    pub device: Option<String>,
+    pub host: Option<CastHost>,
+    /// Overrides the model the device advertises
+    pub model: Option<String>,
+    pub timeout: Duration,
+}
+
 #[derive(Debug, Clone)]
 pub struct App {
     pub port: PortOrRange,
     pub beets_db: Option<PathBuf>,
+    pub unplayable: Unplayable,
+    pub strict: bool,
+    pub sort: Sort,
+    pub shuffle: Option<Shuffle>,
+    pub discovery: Discovery,
     pub cmd: Command,
 }
 
@@ -95,11 +219,10 @@ fn play_command() -> OptionParser<Command> {
         .argument("INDEX")
         .fallback(NonZeroU16::MIN);
     // Should we validate for files/directories early on?
-    // Directories are only handled if there is a single positional arg
     // If passed a list of files, should we accept covers within them?
     // In which case they might apply to all later entries?
     let paths = bpaf::positional::<PathBuf>("path")
-        .help("Paths to play (either a directory or a list of music files)")
+        .help("Paths to play (music files, directories and playlists, in order)")
         .some("Need at least one path to play");
 
     construct!(Command::Play {
@@ -110,20 +233,54 @@ fn play_command() -> OptionParser<Command> {
     .descr("Cast a music directory to a Chromecast device")
 }
 
+fn resume_command() -> OptionParser<Command> {
+    bpaf::pure(Command::Resume)
+        .to_options()
+        .descr("Cast the last played playlist again, from where it stopped")
+}
+
 fn listen_command() -> OptionParser<Command> {
     bpaf::pure(Command::Listen)
         .to_options()
         .descr("Listen to events from the Chromecast device")
 }
 
+fn devices_command() -> OptionParser<Command> {
+    let json = bpaf::long("json")
+        .help("Print the device list as JSON")
+        .switch();
+    construct!(Command::Devices { json })
+        .to_options()
+        .descr("List Cast devices found on the local network")
+}
+
+fn check_command() -> OptionParser<Command> {
+    let json = bpaf::long("json").help("Print the report as JSON").switch();
+    let paths = bpaf::positional::<PathBuf>("path")
+        .help("Paths to check (music files, directories and playlists)")
+        .some("Need at least one path to check");
+    construct!(Command::Check { json, paths })
+        .to_options()
+        .descr("Scan music files and report what would be cast, without casting")
+}
+
 fn parser() -> OptionParser<App> {
     // Subcommands
     let play_cmd = play_command()
         .command("play")
         .help("Cast a music directory to a Chromecast device");
+    let resume_cmd = resume_command()
+        .command("resume")
+        .help("Resume the last session where it stopped");
     let listen_cmd = listen_command()
         .command("listen")
         .help("Listen to events (playback…) from the Chromecast device");
+    let devices_cmd = devices_command()
+        .command("devices")
+        .help("List Cast devices found on the local network");
+    let check_cmd = check_command()
+        .command("check")
+        .help("Report on music files without casting them");
 
     // Common arguments (use a basic-toml conffile at some point)
     let port = bpaf::long("port")
@@ -141,10 +298,85 @@ fn parser() -> OptionParser<App> {
         )
         .argument("PATH")
         .optional();
-    let cmd = construct!([play_cmd, listen_cmd]);
+    let unplayable = bpaf::long("unplayable")
+        .help(
+            "What to do with files beyond what the Chromecast can play \
+            (sample rate, bit depth, channels): skip or transcode",
+        )
+        .argument("ACTION")
+        .fallback(Unplayable::Transcode);
+    let strict = bpaf::long("strict")
+        .help(
+            "Stop queueing at the first file that can't be loaded.\n \
+            By default such files are skipped with a message",
+        )
+        .switch();
+    let sort = bpaf::long("sort")
+        .help(
+            "Order of the files within each directory: \
+            path, tags (album artist, date, album, disc, track), mtime or none.\n \
+            Sorting by tags reads every file before casting starts",
+        )
+        .argument("ORDER")
+        .fallback(Sort::Path);
+    let shuffle_tracks = bpaf::long("shuffle")
+        .help("Play the tracks in random order")
+        .req_flag(Shuffle::Tracks);
+    let shuffle_albums = bpaf::long("shuffle-albums")
+        .help(
+            "Play albums in random order, keeping each album's tracks in order.\n \
+            Albums are told apart by their tags, which reads every file \
+            before casting starts",
+        )
+        .req_flag(Shuffle::Albums);
+    let shuffle = construct!([shuffle_tracks, shuffle_albums]).optional();
+    let device = bpaf::long("device")
+        .help(
+            "Chromecast to use, by friendly name, UUID or host.\n \
+            Without this, the first device to answer is used",
+        )
+        .argument("NAME")
+        .optional();
+    let host = bpaf::long("host")
+        .help(
+            "Connect to the Chromecast at this address, skipping discovery.\n \
+            The port defaults to 8009",
+        )
+        .argument("ADDR[:PORT]")
+        .optional();
+    let model = bpaf::long("model")
+        .help(
+            "Receiver model, as `joujou devices` shows it, for its sample rate \
+            and channel limits.\n \
+            Needed with --host, which can't ask the device; \
+            without it, a Chromecast Audio is assumed",
+        )
+        .argument("MODEL")
+        .optional();
+    let timeout = bpaf::long("discovery-timeout")
+        .help("Give up looking for a Chromecast after SECS seconds")
+        .argument::<f32>("SECS")
+        .guard(
+            |secs| secs.is_finite() && *secs > 0.,
+            "Timeout must be a positive number of seconds",
+        )
+        .map(Duration::from_secs_f32)
+        .fallback(DEFAULT_DISCOVERY_TIMEOUT);
+    let discovery = construct!(Discovery {
+        device,
+        host,
+        model,
+        timeout
+    });
+    let cmd = construct!([play_cmd, resume_cmd, listen_cmd, devices_cmd, check_cmd]);
     construct!(App {
         port,
         beets_db,
+        unplayable,
+        strict,
+        sort,
+        shuffle,
+        discovery,
         cmd
     })
     .to_options()
@@ -0,0 +1,340 @@
+//! Cue sheets, which split a single-file album rip into tracks
+//!
+//! Each track of the sheet becomes a virtual track: the same file,
+//! with its own tags and the span of the file it covers.
+
+use std::path::{Path, PathBuf};
+
+use crate::audio::{AudioFile, Metadata};
+
+// Cue sheet times are in CD frames
+const FRAMES_PER_SECOND: f64 = 75.;
+
+/// The part of a file a virtual track plays, in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: f64,
+    /// None for the last track, which plays to the end of the file
+    pub end: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CueSheet {
+    title: Option<String>,
+    performer: Option<String>,
+    date: Option<String>,
+    files: Vec<CueFile>,
+}
+
+#[derive(Debug, Clone)]
+struct CueFile {
+    name: String,
+    tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone)]
+struct CueTrack {
+    number: u32,
+    title: Option<String>,
+    performer: Option<String>,
+    /// From INDEX 01; the pregap (INDEX 00) plays with the previous track
+    start: Option<f64>,
+}
+
+/// A cue sheet next to an audio file, as "Album.cue" or "Album.flac.cue"
+pub fn find_external(path: &Path) -> Option<PathBuf> {
+    let mut appended = path.as_os_str().to_owned();
+    appended.push(".cue");
+    [path.with_extension("cue"), PathBuf::from(appended)]
+        .into_iter()
+        .find(|cue_path| cue_path.is_file())
+}
+
+impl CueSheet {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::parse(&crate::playlist_file::decode(std::fs::read(
+            path,
+        )?)))
+    }
+
+    /// Lenient parsing, unknown commands are ignored
+    pub fn parse(text: &str) -> Self {
+        let mut sheet = Self::default();
+        for line in text.lines() {
+            let line = line.trim();
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            let track = sheet
+                .files
+                .last_mut()
+                .and_then(|file| file.tracks.last_mut());
+            match &*command.to_ascii_uppercase() {
+                "FILE" => sheet.files.push(CueFile {
+                    name: first_word(rest).to_owned(),
+                    tracks: Vec::new(),
+                }),
+                "TRACK" => {
+                    let mut words = rest.split_whitespace();
+                    let number = words.next().and_then(|n| n.parse().ok());
+                    let audio = words
+                        .next()
+                        .is_some_and(|t| t.eq_ignore_ascii_case("AUDIO"));
+                    if let (Some(number), true, Some(file)) =
+                        (number, audio, sheet.files.last_mut())
+                    {
+                        file.tracks.push(CueTrack {
+                            number,
+                            title: None,
+                            performer: None,
+                            start: None,
+                        });
+                    }
+                }
+                "INDEX" => {
+                    let mut words = rest.split_whitespace();
+                    if let (Some(track), Some("01"), Some(time)) =
+                        (track, words.next(), words.next())
+                    {
+                        track.start = parse_time(time);
+                    }
+                }
+                "TITLE" => match track {
+                    Some(track) => track.title = unquote(rest),
+                    None => sheet.title = unquote(rest),
+                },
+                "PERFORMER" => match track {
+                    Some(track) => track.performer = unquote(rest),
+                    None => sheet.performer = unquote(rest),
+                },
+                "REM" => {
+                    if let Some(("DATE", date)) = rest.split_once(char::is_whitespace) {
+                        sheet.date = unquote(date.trim());
+                    }
+                }
+                _ => (),
+            }
+        }
+        sheet
+    }
+
+    /// The tracks within `path`
+    ///
+    /// Sheets often name the file before it was compressed
+    /// ("Album.wav" for "Album.flac"), match stems if need be.
+    fn tracks_for(&self, path: &Path) -> &[CueTrack] {
+        if let [file] = &self.files[..] {
+            return &file.tracks;
+        }
+        let file = self
+            .files
+            .iter()
+            .find(|file| Path::new(&file.name).file_name() == path.file_name())
+            .or_else(|| {
+                self.files
+                    .iter()
+                    .find(|file| Path::new(&file.name).file_stem() == path.file_stem())
+            });
+        file.map(|file| file.tracks.as_slice()).unwrap_or_default()
+    }
+
+    /// Track tags come from the sheet, album tags only fill gaps
+    fn apply(&self, track: &CueTrack, meta: &mut Metadata) {
+        let cmeta = &mut meta.cast_metadata;
+        cmeta.title = Some(
+            track
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Track {}", track.number)),
+        );
+        cmeta.track_number = Some(track.number);
+        if let Some(performer) = track.performer.as_ref().or(self.performer.as_ref()) {
+            cmeta.artist = Some(performer.clone());
+            meta.lists.artists = vec![performer.clone()];
+        }
+        if cmeta.album_name.is_none() {
+            cmeta.album_name.clone_from(&self.title);
+        }
+        if cmeta.album_artist.is_none() {
+            if let Some(ref performer) = self.performer {
+                cmeta.album_artist = Some(performer.clone());
+                meta.lists.album_artists = vec![performer.clone()];
+            }
+        }
+        if cmeta.release_date.is_none() {
+            cmeta.release_date.clone_from(&self.date);
+        }
+    }
+}
+
+/// Split a file into the tracks of its cue sheet
+///
+/// Files without a cue sheet are returned as-is.
+pub fn split(mut af: AudioFile) -> Vec<AudioFile> {
+    let Some(sheet) = af.cue_sheet.take() else {
+        return vec![af];
+    };
+    let tracks: Vec<(&CueTrack, f64)> = sheet
+        .tracks_for(&af.path)
+        .iter()
+        .filter_map(|track| Some((track, track.start?)))
+        .collect();
+    if tracks.is_empty() {
+        log::warn!("{}: no tracks in the cue sheet", af.path.display());
+        return vec![af];
+    }
+    tracks
+        .iter()
+        .enumerate()
+        .map(|(i, &(track, start))| {
+            let end = tracks.get(i + 1).map(|&(_, next_start)| next_start);
+            let mut vt = af.clone();
+            vt.span = Some(Span { start, end });
+            vt.duration = end.or(af.duration).map(|end| end - start);
+            sheet.apply(track, vt.metadata.get_or_insert_with(Metadata::default));
+            vt
+        })
+        .collect()
+}
+
+/// A quoted string, or the first word
+fn first_word(s: &str) -> &str {
+    match s.strip_prefix('"') {
+        Some(rest) => rest.split_once('"').map_or(rest, |(word, _)| word),
+        None => s.split_whitespace().next().unwrap_or_default(),
+    }
+}
+
+/// Quotes are optional around values with spaces
+fn unquote(s: &str) -> Option<String> {
+    let s = if s.starts_with('"') { first_word(s) } else { s };
+    (!s.is_empty()).then(|| s.to_owned())
+}
+
+/// mm:ss:ff, minutes can go past 59
+fn parse_time(time: &str) -> Option<f64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    let (Some(Some(minutes)), Some(Some(seconds)), Some(Some(frames)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    Some(f64::from(minutes * 60 + seconds) + f64::from(frames) / FRAMES_PER_SECOND)
+}
+
+#[test]
+fn check_cue_sheet() {
+    let sheet = CueSheet::parse(
+        "REM DATE 1999\n\
+        PERFORMER \"Some Band\"\n\
+        TITLE \"Some Album\"\n\
+        FILE \"Some Album.wav\" WAVE\n\
+        \x20 TRACK 01 AUDIO\n\
+        \x20   TITLE \"Intro\"\n\
+        \x20   INDEX 01 00:00:00\n\
+        \x20 TRACK 02 AUDIO\n\
+        \x20   TITLE \"Second\"\n\
+        \x20   PERFORMER \"Guest\"\n\
+        \x20   INDEX 00 03:58:50\n\
+        \x20   INDEX 01 04:00:15\n",
+    );
+    assert_eq!(sheet.title.as_deref(), Some("Some Album"));
+    assert_eq!(sheet.date.as_deref(), Some("1999"));
+    let tracks = sheet.tracks_for(Path::new("/music/Some Album.flac"));
+    assert_eq!(tracks.len(), 2);
+    assert_eq!(tracks[0].start, Some(0.));
+    assert_eq!(tracks[1].start, Some(240.2));
+    assert_eq!(tracks[1].performer.as_deref(), Some("Guest"));
+}
+
+#[cfg(test)]
+fn test_file(path: &str, sheet: &str) -> AudioFile {
+    AudioFile {
+        path: PathBuf::from(path),
+        mime_type: "audio/flac",
+        container: "flac",
+        metadata: None,
+        duration: Some(300.),
+        cover: None,
+        format: Default::default(),
+        transcode: false,
+        cue_sheet: Some(CueSheet::parse(sheet)),
+        span: None,
+    }
+}
+
+#[test]
+fn check_split() {
+    let tracks = split(test_file(
+        "/music/Some Album.flac",
+        "TITLE \"Some Album\"\n\
+        FILE \"Some Album.wav\" WAVE\n\
+        \x20 TRACK 01 AUDIO\n\
+        \x20   TITLE \"Intro\"\n\
+        \x20   INDEX 01 00:00:00\n\
+        \x20 TRACK 02 AUDIO\n\
+        \x20   INDEX 00 03:58:50\n\
+        \x20 TRACK 03 AUDIO\n\
+        \x20   INDEX 01 04:00:15\n",
+    ));
+    // Track 2 has no INDEX 01, and is skipped
+    assert_eq!(tracks.len(), 2);
+    assert_eq!(
+        tracks[0].span,
+        Some(Span {
+            start: 0.,
+            end: Some(240.2)
+        })
+    );
+    assert_eq!(tracks[0].duration, Some(240.2));
+    // The last track plays to the end of the file
+    assert_eq!(
+        tracks[1].span,
+        Some(Span {
+            start: 240.2,
+            end: None
+        })
+    );
+    assert_eq!(tracks[1].duration, Some(300. - 240.2));
+    let cmeta = |i: usize| &tracks[i].metadata.as_ref().unwrap().cast_metadata;
+    assert_eq!(cmeta(0).title.as_deref(), Some("Intro"));
+    assert_eq!(cmeta(1).title.as_deref(), Some("Track 3"));
+    assert_eq!(cmeta(1).track_number, Some(3));
+    assert_eq!(cmeta(1).album_name.as_deref(), Some("Some Album"));
+    assert!(tracks.iter().all(|track| track.cue_sheet.is_none()));
+
+    // Without any INDEX 01, the file plays whole
+    let whole = split(test_file(
+        "/music/Some Album.flac",
+        "FILE \"Some Album.wav\" WAVE\n\
+        \x20 TRACK 01 AUDIO\n\
+        \x20   INDEX 00 00:00:00\n",
+    ));
+    assert_eq!(whole.len(), 1);
+    assert_eq!(whole[0].span, None);
+    assert_eq!(whole[0].duration, Some(300.));
+}
+
+#[test]
+fn check_tracks_for() {
+    let sheet = CueSheet::parse(
+        "FILE \"Disc 1.wav\" WAVE\n\
+        \x20 TRACK 01 AUDIO\n\
+        \x20   INDEX 01 00:00:00\n\
+        FILE \"Disc 2.wav\" WAVE\n\
+        \x20 TRACK 02 AUDIO\n\
+        \x20   INDEX 01 00:00:00\n\
+        \x20 TRACK 03 AUDIO\n\
+        \x20   INDEX 01 03:00:00\n",
+    );
+    let numbers = |path: &str| -> Vec<u32> {
+        sheet
+            .tracks_for(Path::new(path))
+            .iter()
+            .map(|track| track.number)
+            .collect()
+    };
+    assert_eq!(numbers("/music/Disc 1.wav"), [1]);
+    // By stem, when the sheet names the uncompressed file
+    assert_eq!(numbers("/music/Disc 2.flac"), [2, 3]);
+    assert!(numbers("/music/Disc 3.flac").is_empty());
+}
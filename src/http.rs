@@ -1,21 +1,92 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::Cursor;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::Context;
 
 use axum::extract;
 use axum::http::header;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
-use axum_extra::headers::Range;
+use axum_extra::headers::{ETag, IfNoneMatch, Range};
 use axum_extra::TypedHeader;
 use axum_range::{KnownSize, Ranged};
+use rust_cast::channels::media::Image;
 use uuid::Uuid;
 
-#[derive(Debug)]
+use crate::audio::{self, AudioFile, Capabilities, TagLists, Visual};
+use crate::cue::Span;
+use crate::thumbnail;
+use crate::transcode::{BlockingIo, WavStream};
+
+// Extracted and scaled pictures we keep around, in bytes
+const VISUAL_CACHE_SIZE: usize = 64 << 20;
+
+#[derive(Debug, Clone)]
 enum ServedData {
     FileSystem(PathBuf),
-    Memory(Arc<[u8]>),
+    // The picture embedded in an audio file, extracted on request
+    Embedded(PathBuf, [u8; 32]),
+    // Decoded as it is served, within the receiver's limits
+    Transcoded(PathBuf, Capabilities),
+    Thumbnail(Thumbnail),
+}
+
+/// A scaled-down picture, made on request
+#[derive(Debug, Clone)]
+struct Thumbnail {
+    // Index of the full-size picture
+    source: usize,
+    size: u32,
+}
+
+/// Pictures we extracted or scaled, the least recently used go first
+#[derive(Debug)]
+struct VisualCache {
+    // By visual index, with the tick of the last use
+    entries: HashMap<usize, (Arc<[u8]>, u64)>,
+    bytes: usize,
+    capacity: usize,
+    tick: u64,
+}
+
+impl VisualCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            bytes: 0,
+            capacity,
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, id: usize) -> Option<Arc<[u8]>> {
+        self.tick += 1;
+        let (data, used) = self.entries.get_mut(&id)?;
+        *used = self.tick;
+        Some(Arc::clone(data))
+    }
+
+    fn insert(&mut self, id: usize, data: Arc<[u8]>) {
+        self.tick += 1;
+        self.bytes += data.len();
+        if let Some((prev, _)) = self.entries.insert(id, (data, self.tick)) {
+            self.bytes -= prev.len();
+        }
+        // Always keep the latest, even when it is over capacity by itself
+        while self.bytes > self.capacity && self.entries.len() > 1 {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(id, _)| *id)
+                .unwrap();
+            let (data, _) = self.entries.remove(&oldest).unwrap();
+            self.bytes -= data.len();
+        }
+    }
 }
 
 pub fn base_with_path(base: &url::Url, path: &str) -> url::Url {
@@ -24,7 +95,16 @@ pub fn base_with_path(base: &url::Url, path: &str) -> url::Url {
     r
 }
 
+fn memory_response(data: Arc<[u8]>, range: Option<Range>) -> Response {
+    let len = data.len().try_into().unwrap_or(u64::MAX);
+    let body = KnownSize::sized(Cursor::new(data), len);
+    Ranged::new(range, body).into_response()
+}
+
 impl ServedData {
+    /// Serve from the file system
+    ///
+    /// Pictures that need extracting go through `AppState::visual_data`.
     async fn make_response(&self, range: Option<Range>) -> Result<Response, StatusCode> {
         match self {
             Self::FileSystem(path) => {
@@ -36,52 +116,331 @@ impl ServedData {
                     .map_err(|_| StatusCode::NOT_FOUND)?;
                 Ok(Ranged::new(range, body).into_response())
             }
-            Self::Memory(data) => {
-                let body = Cursor::new(Arc::clone(data));
-                let body = KnownSize::sized(body, data.len().try_into().unwrap_or(u64::MAX));
+            Self::Transcoded(path, caps) => {
+                let (path, caps) = (path.clone(), *caps);
+                let stream = tokio::task::spawn_blocking(move || WavStream::open(&path, &caps))
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                    .map_err(|err| {
+                        log::error!("Can't transcode: {err:#}");
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?;
+                let size = stream.size();
+                let body = KnownSize::sized(BlockingIo(stream), size);
                 Ok(Ranged::new(range, body).into_response())
             }
+            Self::Embedded(..) | Self::Thumbnail(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
         }
     }
 }
 
+// Pictures don't change under a given URL
+const VISUAL_CACHE_CONTROL: &str = "public, max-age=86400, immutable";
+
 #[derive(Debug)]
 struct ServedItem {
     mime_type: Cow<'static, str>,
     contents: ServedData,
+    // For tracks, what the Cast metadata can't hold
+    tags: Option<TagLists>,
+    // For tracks of a cue sheet
+    span: Option<Span>,
+    // Strong validator, without the quotes
+    etag: Option<String>,
 }
 
 impl ServedItem {
-    async fn make_response(&self, range: Option<Range>) -> impl IntoResponse {
+    /// Add our headers to `body`, unless the client is up to date
+    ///
+    /// Errors go out as they are, without validators.
+    async fn make_response(
+        &self,
+        if_none_match: Option<IfNoneMatch>,
+        body: impl std::future::Future<Output = Result<Response, StatusCode>>,
+    ) -> Response {
+        let etag = self
+            .etag
+            .as_ref()
+            .and_then(|tag| format!("\"{tag}\"").parse::<ETag>().ok());
+        if let (Some(etag), Some(if_none_match)) = (&etag, if_none_match) {
+            if !if_none_match.precondition_passes(etag) {
+                return (StatusCode::NOT_MODIFIED, TypedHeader(etag.clone())).into_response();
+            }
+        }
+        let body = match body.await {
+            Ok(body) if body.status().is_success() => body,
+            Ok(body) => return body,
+            Err(status) => return status.into_response(),
+        };
         (
             [(header::CONTENT_TYPE, self.mime_type.to_string())],
-            self.contents.make_response(range).await,
+            etag.map(TypedHeader),
+            body,
         )
+            .into_response()
     }
 }
 
+/// From the modification time and size, like most web servers
+fn file_etag(path: &Path) -> Option<String> {
+    let md = std::fs::metadata(path).ok()?;
+    let mtime = md
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    Some(format!("{:x}-{:x}", mtime.as_nanos(), md.len()))
+}
+
+/// Identifies a picture, so that each is served once
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum VisualKey {
+    Content([u8; 32]),
+    File(PathBuf),
+}
+
 #[derive(Debug)]
 struct AppState {
-    tracks: Vec<ServedItem>,
-    visuals: Vec<ServedItem>,
+    // Tracks can be added while casting (MPRIS OpenUri)
+    tracks: RwLock<Vec<Arc<ServedItem>>>,
+    visuals: RwLock<Vec<Arc<ServedItem>>>,
+    // Images for pictures we already serve
+    visual_images: Mutex<HashMap<VisualKey, Vec<Image>>>,
+    visual_cache: Mutex<VisualCache>,
     uuid: Uuid,
+    base: url::Url,
+    caps: Capabilities,
 }
 
 impl AppState {
-    fn new(uuid: Uuid) -> Self {
+    fn new(uuid: Uuid, base: url::Url, caps: Capabilities) -> Self {
         Self {
-            tracks: Vec::new(),
-            visuals: Vec::new(),
+            tracks: RwLock::new(Vec::new()),
+            visuals: RwLock::new(Vec::new()),
+            visual_images: Mutex::new(HashMap::new()),
+            visual_cache: Mutex::new(VisualCache::new(VISUAL_CACHE_SIZE)),
             uuid,
+            base,
+            caps,
         }
     }
+
+    fn add_track(&self, item: ServedItem) -> url::Url {
+        let mut tracks = self.tracks.write().unwrap();
+        let i = tracks.len();
+        tracks.push(Arc::new(item));
+        base_with_path(&self.base, &format!("/{}/track/{i}", self.uuid))
+    }
+
+    fn push_visual(&self, item: ServedItem) -> usize {
+        let mut visuals = self.visuals.write().unwrap();
+        visuals.push(Arc::new(item));
+        visuals.len() - 1
+    }
+
+    fn visual_url(&self, id: usize) -> url::Url {
+        base_with_path(&self.base, &format!("/{}/visual/{id}", self.uuid))
+    }
+
+    /// Serve a picture embedded in `path`, unless identical art is already served
+    ///
+    /// The picture isn't kept, it is extracted again when requested.
+    fn add_embedded_visual(&self, path: &Path, visual: &Visual) -> Vec<Image> {
+        let etag: String = visual.sha256.iter().map(|b| format!("{b:02x}")).collect();
+        self.add_visual(
+            VisualKey::Content(visual.sha256),
+            ServedItem {
+                mime_type: Cow::Owned(visual.media_type.clone()),
+                contents: ServedData::Embedded(path.to_owned(), visual.sha256),
+                tags: None,
+                span: None,
+                etag: Some(etag),
+            },
+            visual.dimensions,
+        )
+    }
+
+    /// Serve a picture file, unless it is already served
+    fn add_visual_file(&self, mime_type: &'static str, path: &Path) -> Vec<Image> {
+        self.add_visual(
+            VisualKey::File(path.to_owned()),
+            ServedItem {
+                mime_type: Cow::Borrowed(mime_type),
+                contents: ServedData::FileSystem(path.to_owned()),
+                tags: None,
+                span: None,
+                etag: file_etag(path),
+            },
+            thumbnail::file_dimensions(path),
+        )
+    }
+
+    /// Serve a picture, and thumbnails of it if it is large
+    ///
+    /// Thumbnails come first, largest first; the default receiver
+    /// shows the first image, the full size one is last.
+    fn add_visual(
+        &self,
+        key: VisualKey,
+        item: ServedItem,
+        dimensions: Option<(u32, u32)>,
+    ) -> Vec<Image> {
+        // Held throughout, so that concurrent additions don't both serve it
+        let mut visual_images = self.visual_images.lock().unwrap();
+        if let Some(images) = visual_images.get(&key) {
+            return images.clone();
+        }
+        let etag = item.etag.clone();
+        let source = self.push_visual(item);
+        let mut images = Vec::new();
+        if let Some(dims) = dimensions {
+            for size in thumbnail::SIZES {
+                if size >= dims.0.max(dims.1) {
+                    continue;
+                }
+                let id = self.push_visual(ServedItem {
+                    mime_type: Cow::Borrowed(thumbnail::MIME_TYPE),
+                    contents: ServedData::Thumbnail(Thumbnail { source, size }),
+                    tags: None,
+                    span: None,
+                    etag: etag.as_ref().map(|tag| format!("{tag}-{size}")),
+                });
+                images.push(Image {
+                    url: self.visual_url(id).into(),
+                    dimensions: Some(thumbnail::scaled(dims, size)),
+                });
+            }
+        }
+        images.push(Image {
+            url: self.visual_url(source).into(),
+            dimensions,
+        });
+        visual_images.insert(key, images.clone());
+        images
+    }
+
+    /// The bytes of a picture we extract or scale, going through the cache
+    ///
+    /// Blocking.
+    fn visual_data(&self, id: usize) -> anyhow::Result<Arc<[u8]>> {
+        if let Some(data) = self.visual_cache.lock().unwrap().get(id) {
+            return Ok(data);
+        }
+        let item = self
+            .visuals
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .context("No such picture")?;
+        let data: Arc<[u8]> = match item.contents {
+            ServedData::FileSystem(ref path) => std::fs::read(path)?.into(),
+            ServedData::Embedded(ref path, ref sha256) => audio::embedded_visual(path, sha256)?
+                .context("The picture is no longer embedded")?
+                .into(),
+            ServedData::Thumbnail(Thumbnail { source, size }) => {
+                thumbnail::make(&self.visual_data(source)?, size)?.into()
+            }
+            ServedData::Transcoded(..) => anyhow::bail!("Not a picture"),
+        };
+        self.visual_cache
+            .lock()
+            .unwrap()
+            .insert(id, Arc::clone(&data));
+        Ok(data)
+    }
+
+    /// Serve an audio file and its art, return the track URL
+    ///
+    /// Embedded front covers come first, then beets art, then cover files.
+    fn add_audio_file(&self, ent: &mut AudioFile) -> url::Url {
+        let etag = file_etag(&ent.path);
+        let (contents, etag) = if ent.transcode {
+            (
+                ServedData::Transcoded(ent.path.clone(), self.caps),
+                etag.map(|tag| format!("{tag}-wav")),
+            )
+        } else {
+            (ServedData::FileSystem(ent.path.clone()), etag)
+        };
+        let url = self.add_track(ServedItem {
+            mime_type: Cow::Borrowed(ent.mime_type),
+            contents,
+            tags: ent.metadata.as_ref().map(|meta| meta.lists.clone()),
+            span: ent.span,
+            etag,
+        });
+        if let Some(ref mut meta) = ent.metadata {
+            let use_visual = meta.has_front_visual() || ent.cover.is_none();
+            let visual = if use_visual { meta.visual.take() } else { None };
+            if let Some(visual) = visual {
+                meta.cast_metadata.images = self.add_embedded_visual(&ent.path, &visual);
+            }
+            if let (true, Some(cover)) = (meta.cast_metadata.images.is_empty(), &ent.cover) {
+                log::debug!("No embedded front cover, using {}", cover.path.display());
+                // Served once, however many tracks use it
+                meta.cast_metadata.images = self.add_visual_file(cover.mime_type, &cover.path);
+            }
+        }
+        url
+    }
+}
+
+/// Lets tracks be added to the HTTP server while it runs
+#[derive(Debug, Clone)]
+pub struct Registry(Arc<AppState>);
+
+impl Registry {
+    /// Serve an audio file and its art, return the track URL
+    pub fn add_audio_file(&self, ent: &mut AudioFile) -> url::Url {
+        self.0.add_audio_file(ent)
+    }
+
+    /// The file served at a track URL
+    pub fn track_path(&self, url: &str) -> Option<PathBuf> {
+        match self.track(url)?.contents {
+            ServedData::FileSystem(ref path) | ServedData::Transcoded(ref path, _) => {
+                Some(path.clone())
+            }
+            ServedData::Embedded(..) | ServedData::Thumbnail(_) => None,
+        }
+    }
+
+    /// Multi-valued tags of the track at a URL
+    pub fn track_tags(&self, url: &str) -> Option<TagLists> {
+        self.track(url)?.tags.clone()
+    }
+
+    /// The part of the file the track at a URL plays, for cue sheets
+    pub fn track_span(&self, url: &str) -> Option<Span> {
+        self.track(url)?.span
+    }
+
+    fn track(&self, url: &str) -> Option<Arc<ServedItem>> {
+        let url = url::Url::parse(url).ok()?;
+        let mut segments = url.path_segments()?;
+        let (Some(uuid), Some("track"), Some(i), None) = (
+            segments.next(),
+            segments.next(),
+            segments.next(),
+            segments.next(),
+        ) else {
+            return None;
+        };
+        if uuid.parse::<Uuid>().ok()? != self.0.uuid {
+            return None;
+        }
+        let tracks = self.0.tracks.read().unwrap();
+        tracks.get(i.parse::<usize>().ok()?).cloned()
+    }
 }
 
 // Uuid must implement serde::Deserialize for Path extraction to compile
 //#[axum::debug_handler]
 async fn serve_one_track(
-    extract::Path((uuid, track_id)): extract::Path<(Uuid, u16)>,
+    extract::Path((uuid, track_id)): extract::Path<(Uuid, usize)>,
     range: Option<TypedHeader<Range>>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
     extract::State(state): extract::State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, StatusCode> {
     if uuid != state.uuid {
@@ -89,15 +448,22 @@ async fn serve_one_track(
     }
     let item = state
         .tracks
-        .get(usize::from(track_id))
+        .read()
+        .unwrap()
+        .get(track_id)
+        .cloned()
         .ok_or(StatusCode::NOT_FOUND)?;
     let range = range.map(|TypedHeader(range)| range);
-    Ok(item.make_response(range).await)
+    let if_none_match = if_none_match.map(|TypedHeader(inm)| inm);
+    Ok(item
+        .make_response(if_none_match, item.contents.make_response(range))
+        .await)
 }
 
 async fn serve_one_visual(
-    extract::Path((uuid, id)): extract::Path<(Uuid, u16)>,
+    extract::Path((uuid, id)): extract::Path<(Uuid, usize)>,
     range: Option<TypedHeader<Range>>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
     extract::State(state): extract::State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, StatusCode> {
     if uuid != state.uuid {
@@ -105,52 +471,49 @@ async fn serve_one_visual(
     }
     let item = state
         .visuals
-        .get(usize::from(id))
+        .read()
+        .unwrap()
+        .get(id)
+        .cloned()
         .ok_or(StatusCode::NOT_FOUND)?;
     let range = range.map(|TypedHeader(range)| range);
-    Ok(item.make_response(range).await)
+    let if_none_match = if_none_match.map(|TypedHeader(inm)| inm);
+    let body = async {
+        if let ServedData::FileSystem(_) = item.contents {
+            return item.contents.make_response(range).await;
+        }
+        let state = Arc::clone(&state);
+        let data = tokio::task::spawn_blocking(move || state.visual_data(id))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|err| {
+                log::error!("Can't load picture {id}: {err:#}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        Ok(memory_response(data, range))
+    };
+    let mut response = item.make_response(if_none_match, body).await;
+    // Errors may be transient, don't have them cached
+    let status = response.status();
+    if status.is_success() || status == StatusCode::NOT_MODIFIED {
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static(VISUAL_CACHE_CONTROL),
+        );
+    }
+    Ok(response)
 }
 
+/// Build the HTTP server
+///
+/// Tracks are added through the `Registry` as they are loaded.
 pub fn make_app(
     uuid: Uuid,
-    playlist: &mut crate::scan::Playlist,
     base: &url::Url,
-) -> axum::routing::Router {
-    let mut state = AppState::new(uuid);
-    let mut default_visual = None;
-    for ent in playlist.entries.iter_mut() {
-        state.tracks.push(ServedItem {
-            mime_type: Cow::Borrowed(ent.mime_type),
-            contents: ServedData::FileSystem(ent.path.clone()),
-        });
-        if let Some(ref mut meta) = ent.metadata {
-            if let Some(visual) = meta.visual.take() {
-                let i = state.visuals.len();
-                state.visuals.push(ServedItem {
-                    mime_type: visual.media_type.into(),
-                    contents: ServedData::Memory(visual.data.into()),
-                });
-                let mut url = base.clone();
-                url.set_path(&format!("/{uuid}/visual/{i}"));
-                meta.cast_metadata.images =
-                    vec![rust_cast::channels::media::Image::new(url.into())];
-            } else if let Some(ref cover) = playlist.cover {
-                let default_visual = default_visual.get_or_insert_with(|| {
-                    log::info!("No embedded cover, using {}", cover.path.display());
-                    let i = state.visuals.len();
-                    state.visuals.push(ServedItem {
-                        mime_type: Cow::Borrowed(cover.mime_type),
-                        contents: ServedData::FileSystem(cover.path.clone()),
-                    });
-                    let mut url = base.clone();
-                    url.set_path(&format!("/{uuid}/visual/{i}"));
-                    rust_cast::channels::media::Image::new(url.into())
-                });
-                meta.cast_metadata.images = vec![default_visual.clone()];
-            }
-        }
-    }
-    axum::Router::new()
+    caps: Capabilities,
+) -> (axum::routing::Router, Registry) {
+    let state = Arc::new(AppState::new(uuid, base.clone(), caps));
+    let router = axum::Router::new()
         .route(
             "/:uuid/track/:track_id",
             axum::routing::get(serve_one_track),
@@ -159,5 +522,22 @@ pub fn make_app(
             "/:uuid/visual/:track_id",
             axum::routing::get(serve_one_visual),
         )
-        .with_state(Arc::new(state))
+        .with_state(Arc::clone(&state));
+    (router, Registry(state))
+}
+
+#[test]
+fn check_visual_cache() {
+    let mut cache = VisualCache::new(10);
+    cache.insert(0, vec![0; 4].into());
+    cache.insert(1, vec![1; 4].into());
+    // Now 1 is the least recently used
+    assert!(cache.get(0).is_some());
+    cache.insert(2, vec![2; 4].into());
+    assert!(cache.get(1).is_none());
+    assert!(cache.get(0).is_some());
+    assert_eq!(cache.bytes, 8);
+    cache.insert(3, vec![3; 20].into());
+    assert_eq!(cache.entries.len(), 1);
+    assert!(cache.get(3).is_some());
 }
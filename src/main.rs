@@ -3,79 +3,111 @@
 use std::future::IntoFuture;
 use std::net::SocketAddr;
 use std::num::NonZeroU16;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use anyhow::Context;
-use rust_cast::channels::media::{Media, MediaQueue, QueueItem, QueueType, RepeatMode, StreamType};
+use rust_cast::channels::media::{MediaQueue, QueueType, RepeatMode};
 use rust_cast::channels::receiver::CastDeviceApp;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::oneshot;
 
 mod audio;
+mod cache;
+mod check;
 mod cli;
+mod cue;
 mod http;
 mod net;
 mod player;
+mod playlist_file;
+mod resume;
 mod scan;
+mod thumbnail;
+mod transcode;
 
 use player::DEFAULT_DESTINATION_ID;
 
+/// Where to start playing
+enum Start {
+    /// 1-based, as in the UI
+    Index(NonZeroU16),
+    /// Within a track, when resuming
+    Track { path: PathBuf, position: f32 },
+}
+
 async fn play<P: AsRef<Path>>(
     paths: &[P],
-    playlist_start: NonZeroU16,
-    port: &cli::PortOrRange,
-    beets_db: Option<&Path>,
+    start: Start,
+    sort: cli::Sort,
+    shuffle: Option<resume::Shuffled>,
+    app: &cli::App,
 ) -> anyhow::Result<()> {
-    let beets_db = if let Some(beets_db) = beets_db {
-        use rusqlite::OpenFlags;
-        Some(rusqlite::Connection::open_with_flags(
-            beets_db,
-            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_EXRESCODE,
-        )?)
-    } else {
-        None
-    };
-
-    let mut playlist;
-    // TODO: loop over args, recurse into directories, take files as-is
-    if let [path] = paths {
-        playlist = scan::dir_to_playlist(path.as_ref(), beets_db.as_ref())?;
-        if playlist.entries.is_empty() {
-            anyhow::bail!("Found no playable entries");
-        }
-    } else {
-        playlist = scan::files_to_playlist(paths, beets_db.as_ref())?;
-    }
-
-    // From 1-based (UI) to 0-based
-    let start_index: u16 = playlist_start.get() - 1;
-    let entlen = playlist.entries.len();
-    if !(..entlen).contains(&start_index.into()) {
-        // greater than is accurate for the 1-based index
-        anyhow::bail!("Playlist start index greater than {}", entlen);
+    let discovery = &app.discovery;
+    // Only lists files, they get loaded as they are queued
+    let mut playlist = scan::paths_to_playlist(paths, sort, app.beets_db.as_deref())?;
+    if let Some(shuffled) = shuffle {
+        playlist.shuffle(shuffled.shuffle, shuffled.seed, app.beets_db.as_deref())?;
     }
-    for entry in playlist.entries.iter() {
-        println!("{}", entry.path.display());
+    if playlist.entries.is_empty() {
+        anyhow::bail!("Found no audio files");
     }
     // XXX I would like mdns-sd to tell on which interface services
     // are discovered, so I can expose sender only on these (SO_BINDTODEVICE).
     // XXX This is one-shot
-    let (remote_address, remote_port) = net::discover()
+    let target = net::locate(discovery)
         .await
         .with_context(|| "Could not find Chromecast.")?;
+    if target.model.is_none() {
+        eprintln!(
+            "The receiver model is unknown, assuming a Chromecast Audio's limits \
+            (pass --model to set it)"
+        );
+    }
+    let caps = audio::Capabilities::for_model(target.model.as_deref());
+
+    let (start_index, start_position): (usize, _) = match start {
+        Start::Index(playlist_start) => {
+            // From 1-based (UI) to 0-based
+            let start_index = playlist_start.get() - 1;
+            let entlen = playlist.entries.len();
+            if !(..entlen).contains(&start_index.into()) {
+                // greater than is accurate for the 1-based index
+                anyhow::bail!("Playlist start index greater than {}", entlen);
+            }
+            (start_index.into(), None)
+        }
+        Start::Track { path, position } => {
+            if let Some(i) = playlist.entries.iter().position(|ent| ent.path == path) {
+                (i, Some((path, position)))
+            } else {
+                log::warn!(
+                    "{} is no longer in the playlist, starting over",
+                    path.display()
+                );
+                (0, None)
+            }
+        }
+    };
+    let session_file = resume::SessionFile::new(paths, sort, shuffle)
+        .map_err(|err| log::warn!("Not saving the session: {err:#}"))
+        .ok();
+    for entry in playlist.entries.iter() {
+        println!("{}", entry.path.display());
+    }
     // XXX Could I access the socket and call socket2 local_addr
     // (libc getsockname)?  CastDevice builds the TcpStream
     // but does not expose it.
     let device = rust_cast::CastDevice::connect_without_host_verification(
-        remote_address.clone(),
-        remote_port,
+        target.address.clone(),
+        target.port,
     )
     .await?;
-    let mut tcp1 = tokio::net::TcpStream::connect((remote_address.as_str(), remote_port)).await?;
+    let mut tcp1 = tokio::net::TcpStream::connect((target.address.as_str(), target.port)).await?;
     let local_addr = tcp1.local_addr()?;
     tcp1.shutdown().await?;
 
-    let listener = net::bind(&local_addr, port).await?;
+    let listener = net::bind(&local_addr, &app.port).await?;
     // Like local_addr but with the effective port
     let mut expose_addr = listener.local_addr()?;
     // Clear scope_id, Display would expose it but it's host-internal
@@ -84,7 +116,7 @@ async fn play<P: AsRef<Path>>(
     }
     let base = format!("http://{expose_addr}").parse().unwrap();
     let uuid = uuid::Uuid::new_v4();
-    let server = http::make_app(uuid, &mut playlist, &base);
+    let (server, registry) = http::make_app(uuid, &base, caps);
 
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
     let join_server = tokio::spawn(
@@ -99,6 +131,54 @@ async fn play<P: AsRef<Path>>(
         .await?;
     //device.heartbeat.ping()?;
 
+    // The queue starts with the start track, tracks on either side
+    // get loaded as playback gets near them
+    let mut earlier = playlist.entries;
+    let later = earlier.split_off(start_index).into();
+    let serving = player::Serving {
+        registry,
+        caps,
+        unplayable: app.unplayable,
+        strict: app.strict,
+        pending: Mutex::new(player::Pending {
+            earlier,
+            later,
+            beets_db: app.beets_db.clone(),
+        }),
+    };
+    let items = serving.load_pending(player::QUEUE_BATCH, player::QueueEnd::End)?;
+    // When resuming within a cue sheet, the track the position falls in
+    let first_index = start_position.as_ref().map_or(0, |&(_, position)| {
+        items
+            .iter()
+            .position(|it| {
+                !serving
+                    .registry
+                    .track_span(&it.media.content_id)
+                    .and_then(|span| span.end)
+                    .is_some_and(|end| f64::from(position) >= end)
+            })
+            .unwrap_or_default()
+    });
+    let Some(first) = items.get(first_index) else {
+        anyhow::bail!("Found no playable entries");
+    };
+    // Unless the start track was skipped
+    let start_position = start_position
+        .filter(|(path, _)| {
+            serving
+                .registry
+                .track_path(&first.media.content_id)
+                .as_ref()
+                == Some(path)
+        })
+        .map(|(_, position)| position)
+        .or_else(|| {
+            // Cue sheet tracks start within the file
+            let span = serving.registry.track_span(&first.media.content_id)?;
+            Some(span.start as f32).filter(|&start| start > 0.)
+        });
+
     let app = device
         .receiver
         .launch_app(&CastDeviceApp::DefaultMediaReceiver)
@@ -107,24 +187,8 @@ async fn play<P: AsRef<Path>>(
     log::info!("App transport_id {}", app.transport_id);
     device.connection.connect(app.transport_id.as_str()).await?;
     let media_queue = MediaQueue {
-        items: playlist
-            .entries
-            .into_iter()
-            .enumerate()
-            .map(|(i, ent)| QueueItem {
-                media: Media {
-                    content_id: http::base_with_path(&base, &format!("/{uuid}/track/{i}")).into(),
-                    stream_type: StreamType::Buffered,
-                    content_type: ent.mime_type.to_owned(),
-                    metadata: ent
-                        .metadata
-                        .map(|m| rust_cast::channels::media::Metadata::MusicTrack(m.cast_metadata)),
-                    duration: None,
-                },
-                item_id: None,
-            })
-            .collect(),
-        start_index,
+        items,
+        start_index: first_index.try_into()?,
         queue_type: QueueType::Playlist,
         repeat_mode: RepeatMode::Off,
     };
@@ -133,30 +197,51 @@ async fn play<P: AsRef<Path>>(
         .load_queue(&app.transport_id, &app.session_id, &media_queue)
         .await?;
     let media_status = status.entries.remove(0);
+    if let Some(position) = start_position {
+        device
+            .media
+            .seek(
+                &app.transport_id,
+                media_status.media_session_id,
+                Some(position),
+                None,
+                None,
+            )
+            .await?;
+    }
     let receiver_status = device.receiver.get_status().await?;
-    let player =
-        player::Player::from_status(device, app.transport_id, media_status, receiver_status);
+    let player = player::Player::from_status(
+        device,
+        app.transport_id,
+        media_status,
+        receiver_status,
+        Some(serving),
+        target,
+        media_queue.items,
+    );
     let busname = format!("com.github.g2p.joujou.u{uuid}");
-    let mpris_server = mpris_server::Server::new(&busname, player).await?;
+    let mpris_server = mpris_server::Server::new_with_track_list(&busname, player).await?;
     // XXX mpris-server is lacking a way
     // to close the connection and await that.
-    player::run_player(&mpris_server).await;
+    player::run_player(&mpris_server, session_file.as_ref()).await;
     log::debug!("Shutting down our HTTP server");
     shutdown_tx.send(()).unwrap();
     join_server.await??;
     Ok(())
 }
 
-async fn listen() -> anyhow::Result<()> {
-    let (remote_address, remote_port) = net::discover()
+async fn listen(discovery: &cli::Discovery) -> anyhow::Result<()> {
+    let target = net::locate(discovery)
         .await
         .with_context(|| "Could not find Chromecast.")?;
     // XXX Could I access the socket and call socket2 local_addr
     // (libc getsockname)?  CastDevice builds the TcpStream
     // but does not expose it.
-    let device =
-        rust_cast::CastDevice::connect_without_host_verification(remote_address, remote_port)
-            .await?;
+    let device = rust_cast::CastDevice::connect_without_host_verification(
+        target.address.clone(),
+        target.port,
+    )
+    .await?;
     println!("Connecting to device and {}", DEFAULT_DESTINATION_ID);
     device
         .connection
@@ -194,11 +279,36 @@ async fn listen() -> anyhow::Result<()> {
         app.transport_id.to_owned(),
         media_status,
         receiver_status,
+        None,
+        target,
+        Vec::new(),
     );
     let uuid = uuid::Uuid::new_v4();
     let busname = format!("com.github.g2p.joujou.u{uuid}");
-    let mpris_server = mpris_server::Server::new(&busname, player).await?;
-    player::run_player(&mpris_server).await;
+    let mpris_server = mpris_server::Server::new_with_track_list(&busname, player).await?;
+    player::run_player(&mpris_server, None).await;
+    Ok(())
+}
+
+async fn devices(json: bool) -> anyhow::Result<()> {
+    let devices = net::list_devices(net::LISTING_WINDOW).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+    if devices.is_empty() {
+        eprintln!("No Cast devices found");
+    }
+    for dev in devices {
+        println!("{}", dev.friendly_name.as_deref().unwrap_or(&dev.fullname));
+        println!("  model: {}", dev.model.as_deref().unwrap_or("?"));
+        println!("  id: {}", dev.id.as_deref().unwrap_or("?"));
+        println!("  host: {}", dev.hostname.trim_end_matches('.'));
+        for addr in dev.addresses.iter() {
+            println!("  address: {addr}");
+        }
+        println!("  port: {}", dev.port);
+    }
     Ok(())
 }
 
@@ -209,9 +319,39 @@ async fn main() -> anyhow::Result<()> {
     let app = cli::parse_cli();
     match app.cmd {
         cli::Command::Play {
-            paths,
+            ref paths,
             playlist_start,
-        } => play(&paths, playlist_start, &app.port, app.beets_db.as_deref()).await,
-        cli::Command::Listen => listen().await,
+        } => {
+            play(
+                paths,
+                Start::Index(playlist_start),
+                app.sort,
+                app.shuffle.map(resume::Shuffled::new),
+                &app,
+            )
+            .await
+        }
+        cli::Command::Resume => {
+            let saved = resume::SessionFile::load()?;
+            play(
+                &saved.paths,
+                Start::Track {
+                    path: saved.track,
+                    position: saved.position,
+                },
+                // The saved order, so that the tracks left are the same
+                saved.sort.unwrap_or(app.sort),
+                saved
+                    .shuffle
+                    .or_else(|| app.shuffle.map(resume::Shuffled::new)),
+                &app,
+            )
+            .await
+        }
+        cli::Command::Listen => listen(&app.discovery).await,
+        cli::Command::Devices { json } => devices(json).await,
+        cli::Command::Check { ref paths, json } => {
+            check::check(paths, app.beets_db.as_deref(), app.sort, json)
+        }
     }
 }
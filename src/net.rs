@@ -1,13 +1,110 @@
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
 
-use mdns_sd::{ServiceDaemon, ServiceEvent};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
 use tokio::net::TcpListener;
+use tokio::time::Instant;
 
-use crate::cli::PortOrRange;
+use crate::cli::{Discovery, PortOrRange};
 
 // I'd like rust_cast to export those constants
 const SERVICE_TYPE: &str = "_googlecast._tcp.local.";
 
+// How long to keep collecting answers when picking a device by name,
+// so that ambiguous selectors can be detected
+const SELECTION_WINDOW: Duration = Duration::from_secs(3);
+
+// How long `joujou devices` listens for answers
+pub const LISTING_WINDOW: Duration = Duration::from_secs(3);
+
+/// A Cast device, as advertised over mDNS
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub fullname: String,
+    pub hostname: String,
+    /// The `fn` TXT record
+    pub friendly_name: Option<String>,
+    /// The `md` TXT record
+    pub model: Option<String>,
+    /// The `id` TXT record, a UUID without dashes
+    pub id: Option<String>,
+    /// Sorted, IPv4 first
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+}
+
+impl DeviceInfo {
+    fn from_service_info(info: &ServiceInfo) -> Self {
+        let txt = |key| info.get_property_val_str(key).map(str::to_owned);
+        let mut addresses = info.get_addresses().iter().copied().collect::<Vec<_>>();
+        addresses.sort();
+        Self {
+            fullname: info.get_fullname().to_owned(),
+            hostname: info.get_hostname().to_owned(),
+            friendly_name: txt("fn"),
+            model: txt("md"),
+            id: txt("id"),
+            addresses,
+            port: info.get_port(),
+        }
+    }
+
+    /// The address we connect to
+    pub fn address(&self) -> String {
+        self.addresses[0].to_string()
+    }
+
+    /// Match a user-provided selector against friendly name, UUID or host
+    fn matches(&self, selector: &str) -> bool {
+        if let Some(ref name) = self.friendly_name {
+            if name.eq_ignore_ascii_case(selector) {
+                return true;
+            }
+        }
+        if let Some(ref id) = self.id {
+            // Accept the usual dashed UUID form as well
+            let sel_id = selector.replace('-', "");
+            if id.replace('-', "").eq_ignore_ascii_case(&sel_id) {
+                return true;
+            }
+        }
+        let host = self.hostname.trim_end_matches('.');
+        if host.eq_ignore_ascii_case(selector.trim_end_matches('.'))
+            || host
+                .strip_suffix(".local")
+                .is_some_and(|h| h.eq_ignore_ascii_case(selector))
+        {
+            return true;
+        }
+        if let Ok(addr) = selector.parse::<IpAddr>() {
+            return self.addresses.contains(&addr);
+        }
+        false
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "\"{}\" ({}, id {}) at {}",
+            self.friendly_name.as_deref().unwrap_or("?"),
+            self.model.as_deref().unwrap_or("unknown model"),
+            self.id.as_deref().unwrap_or("?"),
+            self.hostname.trim_end_matches('.'),
+        )
+    }
+}
+
+fn candidate_list<'a>(devices: impl IntoIterator<Item = &'a DeviceInfo>) -> String {
+    devices
+        .into_iter()
+        .map(|dev| format!("\n  {dev}"))
+        .collect()
+}
+
 pub async fn bind(local_addr: &SocketAddr, port: &PortOrRange) -> std::io::Result<TcpListener> {
     // Rebuild with only the stuff we want
     // (we could also just clear port and v6 flow info)
@@ -41,33 +138,188 @@ pub async fn bind(local_addr: &SocketAddr, port: &PortOrRange) -> std::io::Resul
     }
 }
 
-pub async fn discover() -> anyhow::Result<(String, u16)> {
+/// Find a Chromecast
+///
+/// Without a selector, the first device to answer wins.
+/// With a selector, answers are collected for a few seconds
+/// and exactly one device must match.
+/// Fails if no device answered within `timeout`.
+pub async fn discover(selector: Option<&str>, timeout: Duration) -> anyhow::Result<DeviceInfo> {
     let mdns = ServiceDaemon::new()?;
 
     let receiver = mdns.browse(SERVICE_TYPE)?;
 
-    // TODO timeout support
+    let mut devices: Vec<DeviceInfo> = Vec::new();
+    let give_up = Instant::now() + timeout;
+    let mut until = (Instant::now() + SELECTION_WINDOW).min(give_up);
+    // Waiting past the selection window for a first answer
+    let mut late = false;
     loop {
-        let event = receiver.recv_async().await?;
+        let event = match tokio::time::timeout_at(until, receiver.recv_async()).await {
+            Ok(event) => event?,
+            // Keep waiting if nothing answered at all
+            Err(_) if devices.is_empty() && until < give_up => {
+                until = give_up;
+                late = true;
+                continue;
+            }
+            Err(_) if devices.is_empty() => {
+                mdns.shutdown()?.recv_async().await?;
+                anyhow::bail!(
+                    "No Chromecast answered within {}s \
+                    (is multicast blocked? --host skips discovery)",
+                    timeout.as_secs_f32()
+                );
+            }
+            Err(_) => break,
+        };
         match event {
             ServiceEvent::ServiceResolved(info) => {
-                let mut addresses = info
-                    .get_addresses()
-                    .iter()
-                    .map(|address| address.to_string())
-                    .collect::<Vec<_>>();
+                let device = DeviceInfo::from_service_info(&info);
                 log::info!(
                     "Resolved a new service: {} ({})",
                     info.get_fullname(),
-                    addresses.join(", ")
+                    device
+                        .addresses
+                        .iter()
+                        .map(IpAddr::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
                 );
+                if device.addresses.is_empty() {
+                    continue;
+                }
+                if selector.is_none() {
+                    mdns.shutdown()?.recv_async().await?;
+                    return Ok(device);
+                }
+                // Services get resolved again when records are refreshed
+                devices.retain(|dev| dev.fullname != device.fullname);
+                devices.push(device);
+                if late {
+                    // Other devices may answer just after the first one
+                    late = false;
+                    until = (Instant::now() + SELECTION_WINDOW).min(give_up);
+                }
+            }
+            other_event => {
+                log::info!("Received other service event: {:?}", other_event);
+            }
+        }
+    }
+    mdns.shutdown()?.recv_async().await?;
 
-                mdns.shutdown()?.recv_async().await?;
-                return Ok((addresses.remove(0), info.get_port()));
+    // Not reached without a selector
+    let selector = selector.unwrap();
+    let mut matching = devices.iter().filter(|dev| dev.matches(selector));
+    match (matching.next(), matching.next()) {
+        (Some(dev), None) => Ok(dev.clone()),
+        (None, _) => anyhow::bail!(
+            "No Chromecast matches {selector:?}, candidates are:{}",
+            candidate_list(&devices)
+        ),
+        (Some(_), Some(_)) => anyhow::bail!(
+            "Several Chromecasts match {selector:?}:{}",
+            candidate_list(devices.iter().filter(|dev| dev.matches(selector)))
+        ),
+    }
+}
+
+/// Where to connect to the Chromecast
+pub struct Target {
+    pub address: String,
+    pub port: u16,
+    /// Unknown when discovery was skipped, unless given
+    pub model: Option<String>,
+    /// Finds this same device again, by its UUID or host
+    rediscovery: Discovery,
+}
+
+impl Target {
+    /// Resolve the device again, its address may have changed
+    ///
+    /// Never picks another device, even when any would do at start-up.
+    pub async fn relocate(&self) -> anyhow::Result<Self> {
+        locate(&self.rediscovery).await
+    }
+}
+
+/// The Chromecast, either given or discovered
+pub async fn locate(discovery: &Discovery) -> anyhow::Result<Target> {
+    if let Some(ref host) = discovery.host {
+        log::info!("Skipping discovery, using {}:{}", host.host, host.port);
+        return Ok(Target {
+            address: host.host.clone(),
+            port: host.port,
+            model: discovery.model.clone(),
+            rediscovery: discovery.clone(),
+        });
+    }
+    let device = discover(discovery.device.as_deref(), discovery.timeout).await?;
+    log::info!("Using {device}");
+    let rediscovery = Discovery {
+        device: Some(device.id.clone().unwrap_or_else(|| device.hostname.clone())),
+        ..discovery.clone()
+    };
+    Ok(Target {
+        address: device.address(),
+        port: device.port,
+        model: discovery.model.clone().or(device.model),
+        rediscovery,
+    })
+}
+
+/// Browse for Cast devices during `window`, return everything that answered
+pub async fn list_devices(window: Duration) -> anyhow::Result<Vec<DeviceInfo>> {
+    let mdns = ServiceDaemon::new()?;
+
+    let receiver = mdns.browse(SERVICE_TYPE)?;
+
+    let mut devices: Vec<DeviceInfo> = Vec::new();
+    let until = Instant::now() + window;
+    while let Ok(event) = tokio::time::timeout_at(until, receiver.recv_async()).await {
+        match event? {
+            ServiceEvent::ServiceResolved(info) => {
+                let device = DeviceInfo::from_service_info(&info);
+                devices.retain(|dev| dev.fullname != device.fullname);
+                devices.push(device);
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                devices.retain(|dev| dev.fullname != fullname);
             }
             other_event => {
                 log::info!("Received other service event: {:?}", other_event);
             }
         }
     }
+    mdns.shutdown()?.recv_async().await?;
+
+    devices.sort_by(|a, b| {
+        natord::compare(
+            a.friendly_name.as_deref().unwrap_or_default(),
+            b.friendly_name.as_deref().unwrap_or_default(),
+        )
+        .then_with(|| a.fullname.cmp(&b.fullname))
+    });
+    Ok(devices)
+}
+
+#[test]
+fn check_device_matching() {
+    let dev = DeviceInfo {
+        fullname: "Chromecast-Audio-0123._googlecast._tcp.local.".to_owned(),
+        hostname: "0123abcd.local.".to_owned(),
+        friendly_name: Some("Living Room".to_owned()),
+        model: Some("Chromecast Audio".to_owned()),
+        id: Some("0123456789abcdef0123456789abcdef".to_owned()),
+        addresses: vec!["192.168.1.20".parse().unwrap()],
+        port: 8009,
+    };
+    assert!(dev.matches("living room"));
+    assert!(dev.matches("01234567-89AB-CDEF-0123-456789ABCDEF"));
+    assert!(dev.matches("0123abcd"));
+    assert!(dev.matches("0123abcd.local"));
+    assert!(dev.matches("192.168.1.20"));
+    assert!(!dev.matches("Kitchen"));
+    assert!(!dev.matches("192.168.1.21"));
 }
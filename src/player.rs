@@ -1,5 +1,8 @@
+use std::collections::VecDeque;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use arc_swap::ArcSwap;
 use mpris_server::{PlaybackStatus, Property};
@@ -7,45 +10,194 @@ use rust_cast::channels::connection::ConnectionResponse;
 use rust_cast::channels::heartbeat::HeartbeatResponse;
 use rust_cast::channels::media::Metadata::MusicTrack;
 use rust_cast::channels::media::{
-    ExtendedPlayerState, ExtendedStatus, MediaResponse, PlayerState, RepeatMode, StatusEntry,
+    ExtendedPlayerState, ExtendedStatus, IdleReason, Media, MediaQueue, MediaResponse, PlayerState,
+    QueueItem, QueueType, RepeatMode, StatusEntry, StreamType,
 };
-use rust_cast::channels::receiver;
+use rust_cast::channels::receiver::{self, CastDeviceApp};
 use rust_cast::{CastDevice, ChannelMessage};
 use tokio::sync::Notify;
 
+use crate::audio::{AudioFile, Capabilities, TagLists};
+use crate::resume::SessionFile;
+use crate::{cli, cue, http, net, scan};
+
 mod mpris;
 
 // I'd like rust_cast to export those constants
 pub const DEFAULT_DESTINATION_ID: &str = "receiver-0";
 
-pub struct Player<'a> {
-    pub receiver: CastDevice<'a>,
+// How often to save our position while playing
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(15);
+
+// Reconnection attempts after the connection drops, with backoff
+const RECONNECT_ATTEMPTS: u32 = 8;
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Listed tracks are loaded and queued this many at a time,
+// when playback gets within QUEUE_MARGIN items of the end of the queue
+pub const QUEUE_BATCH: usize = 20;
+const QUEUE_MARGIN: usize = 3;
+
+// Cue sheet tracks end this close to their end time, status updates lag
+const SPAN_END_SLACK: f64 = 0.25;
+
+/// The connection to a media session on the receiver
+///
+/// Replaced as a whole when reconnecting.
+pub struct Session<'a> {
+    pub device: CastDevice<'a>,
     pub transport_id: String,
     pub media_session_id: i32,
+}
+
+/// What we need to serve files added while casting
+pub struct Serving {
+    pub registry: http::Registry,
+    pub caps: Capabilities,
+    pub unplayable: cli::Unplayable,
+    pub strict: bool,
+    pub pending: Mutex<Pending>,
+}
+
+/// Listed files that aren't in the Cast queue yet
+pub struct Pending {
+    /// Before the start of the queue, in playlist order
+    pub earlier: Vec<scan::Entry>,
+    /// After the end of the queue
+    pub later: VecDeque<scan::Entry>,
+    pub beets_db: Option<PathBuf>,
+}
+
+/// Which end of the queue pending files are loaded for
+#[derive(Debug, Clone, Copy)]
+pub enum QueueEnd {
+    Start,
+    End,
+}
+
+impl Serving {
+    /// Load up to `count` playable files next to one end of the queue,
+    /// and serve them
+    ///
+    /// Items are in playlist order.  Files that fail to load are skipped,
+    /// and reported together; unless strict, in which case nothing more
+    /// gets loaded on that side.
+    pub fn load_pending(&self, count: usize, end: QueueEnd) -> anyhow::Result<Vec<QueueItem>> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut items = Vec::new();
+        let mut failures = Vec::new();
+        loop {
+            let left = match end {
+                QueueEnd::Start => pending.earlier.len(),
+                QueueEnd::End => pending.later.len(),
+            };
+            if items.len() >= count || left == 0 {
+                break;
+            }
+            let n = (count - items.len()).min(left);
+            let batch: Vec<scan::Entry> = match end {
+                QueueEnd::Start => pending.earlier.drain(left - n..).collect(),
+                QueueEnd::End => pending.later.drain(..n).collect(),
+            };
+            let loaded = tokio::task::block_in_place(|| {
+                scan::load_entries(&batch, pending.beets_db.as_deref())
+            })?;
+            let mut batch_items = Vec::new();
+            for (listed, loaded) in batch.iter().zip(loaded) {
+                let mut ent = match loaded {
+                    Ok(ent) => ent,
+                    Err(err) if self.strict => {
+                        match end {
+                            QueueEnd::Start => pending.earlier.clear(),
+                            QueueEnd::End => pending.later.clear(),
+                        }
+                        return Err(err.context(listed.path.display().to_string()));
+                    }
+                    Err(error) => {
+                        failures.push(scan::Failure {
+                            path: listed.path.clone(),
+                            error,
+                        });
+                        continue;
+                    }
+                };
+                if !ent.fit(&self.caps, self.unplayable) {
+                    continue;
+                }
+                batch_items.extend(self.serve(ent));
+            }
+            match end {
+                QueueEnd::Start => {
+                    items.splice(0..0, batch_items);
+                }
+                QueueEnd::End => items.extend(batch_items),
+            }
+        }
+        scan::report_failures(&failures);
+        Ok(items)
+    }
+
+    /// Serve a loaded file, as one item per track of its cue sheet if any
+    fn serve(&self, ent: AudioFile) -> Vec<QueueItem> {
+        cue::split(ent)
+            .into_iter()
+            .map(|mut ent| {
+                let url = self.registry.add_audio_file(&mut ent);
+                queue_item(ent, url)
+            })
+            .collect()
+    }
+}
+
+pub struct Player<'a> {
+    session: ArcSwap<Session<'a>>,
     media_status: ArcSwap<StatusEntry>,
     media_status_change: Notify,
     receiver_status: ArcSwap<receiver::Status>,
     receiver_status_change: Notify,
+    // Only when we run the HTTP server
+    serving: Option<Serving>,
+    // To find the device again after a disconnection
+    target: net::Target,
+    // What we loaded, in case the receiver forgets it while we are away
+    // Empty when we joined someone else's session
+    queue: Mutex<Vec<QueueItem>>,
+    // The item we last moved to where its cue sheet track starts,
+    // receivers start every item at the start of the file
+    span_item_id: Mutex<Option<i32>>,
 }
 
 impl<'a> Player<'a> {
     pub fn from_status(
-        receiver: CastDevice<'a>,
+        device: CastDevice<'a>,
         transport_id: String,
         media_status: StatusEntry,
         receiver_status: receiver::Status,
+        serving: Option<Serving>,
+        target: net::Target,
+        queue: Vec<QueueItem>,
     ) -> Self {
         Self {
-            receiver,
-            transport_id,
-            media_session_id: media_status.media_session_id,
+            session: ArcSwap::from_pointee(Session {
+                device,
+                transport_id,
+                media_session_id: media_status.media_session_id,
+            }),
             media_status: ArcSwap::from_pointee(media_status),
             media_status_change: Notify::new(),
             receiver_status: ArcSwap::from_pointee(receiver_status),
             receiver_status_change: Notify::new(),
+            span_item_id: Mutex::new(media_status.current_item_id),
+            serving,
+            target,
+            queue: Mutex::new(queue),
         }
     }
 
+    fn session(&self) -> Arc<Session<'a>> {
+        self.session.load_full()
+    }
+
     fn media_status(&self) -> impl Deref<Target = Arc<StatusEntry>> {
         self.media_status.load()
     }
@@ -86,55 +238,374 @@ impl<'a> Player<'a> {
     }
 
     async fn next(&self) -> Result<(), rust_cast::errors::Error> {
-        let ms = self
-            .receiver
+        let session = self.session();
+        let ms = session
+            .device
             .media
-            .next(&self.transport_id, self.media_session_id)
+            .next(&session.transport_id, session.media_session_id)
             .await?;
         self.set_media_status(ms);
         Ok(())
     }
 
     async fn prev(&self) -> Result<(), rust_cast::errors::Error> {
-        let ms = self
-            .receiver
+        let session = self.session();
+        let ms = session
+            .device
             .media
-            .prev(&self.transport_id, self.media_session_id)
+            .prev(&session.transport_id, session.media_session_id)
             .await?;
         self.set_media_status(ms);
         Ok(())
     }
 
     async fn play(&self) -> Result<(), rust_cast::errors::Error> {
-        let ms = self
-            .receiver
+        let session = self.session();
+        let ms = session
+            .device
             .media
-            .play(&self.transport_id, self.media_session_id)
+            .play(&session.transport_id, session.media_session_id)
             .await?;
         self.set_media_status(ms);
         Ok(())
     }
 
     async fn pause(&self) -> Result<(), rust_cast::errors::Error> {
-        let ms = self
-            .receiver
+        let session = self.session();
+        let ms = session
+            .device
+            .media
+            .pause(&session.transport_id, session.media_session_id)
+            .await?;
+        self.set_media_status(ms);
+        Ok(())
+    }
+
+    async fn jump(&self, item_id: i32) -> Result<(), rust_cast::errors::Error> {
+        let session = self.session();
+        let ms = session
+            .device
             .media
-            .pause(&self.transport_id, self.media_session_id)
+            .queue_jump(&session.transport_id, session.media_session_id, item_id)
             .await?;
         self.set_media_status(ms);
         Ok(())
     }
 
+    async fn remove_items(&self, item_ids: &[i32]) -> Result<(), rust_cast::errors::Error> {
+        let removed = item_ids
+            .iter()
+            .filter_map(|&id| self.item_content_id(id))
+            .collect::<Vec<_>>();
+        let session = self.session();
+        let ms = session
+            .device
+            .media
+            .queue_remove(&session.transport_id, session.media_session_id, item_ids)
+            .await?;
+        self.queue
+            .lock()
+            .unwrap()
+            .retain(|it| !removed.contains(&it.media.content_id));
+        self.set_media_status(ms);
+        Ok(())
+    }
+
+    /// Load a local file and insert it in the Cast queue
+    ///
+    /// Returns the item id it was given, if the receiver told us;
+    /// for files with a cue sheet, that of the first track.
+    async fn enqueue(
+        &self,
+        path: PathBuf,
+        insert_before: Option<i32>,
+    ) -> anyhow::Result<Option<i32>> {
+        let Some(ref serving) = self.serving else {
+            anyhow::bail!("Not serving files");
+        };
+        let beets_db = serving.pending.lock().unwrap().beets_db.clone();
+        let mut ent = tokio::task::spawn_blocking(move || {
+            // As if its directory had been listed
+            let entry = scan::Entry {
+                cover: path.parent().and_then(scan::dir_cover),
+                ..scan::Entry::new(path)
+            };
+            scan::load_entries(std::slice::from_ref(&entry), beets_db.as_deref())?.remove(0)
+        })
+        .await??;
+        if !ent.fit(&serving.caps, serving.unplayable) {
+            anyhow::bail!("{} can't be played", ent.path.display());
+        }
+        log::info!("Enqueuing {}", ent.path.display());
+        let items = serving.serve(ent);
+        self.insert_items(items, insert_before).await
+    }
+
+    /// Insert items in the Cast queue, and in our copy of it
+    ///
+    /// Returns the item id the first one was given, if the receiver told us.
+    async fn insert_items(
+        &self,
+        items: Vec<QueueItem>,
+        insert_before: Option<i32>,
+    ) -> anyhow::Result<Option<i32>> {
+        let known = self.item_ids();
+        let session = self.session();
+        let ms = session
+            .device
+            .media
+            .queue_insert(
+                &session.transport_id,
+                session.media_session_id,
+                &items,
+                insert_before,
+            )
+            .await?;
+        {
+            let mut queue = self.queue.lock().unwrap();
+            let pos = insert_before
+                .and_then(|id| self.item_content_id(id))
+                .and_then(|cid| queue.iter().position(|it| it.media.content_id == cid))
+                .unwrap_or(queue.len());
+            queue.splice(pos..pos, items);
+        }
+        let item_id = ms
+            .items
+            .iter()
+            .flatten()
+            .filter_map(|it| it.item_id)
+            .find(|id| !known.contains(id));
+        self.set_media_status(ms);
+        Ok(item_id)
+    }
+
+    /// Queue more of the listed files when playback gets near either end
+    async fn top_up(&self) -> anyhow::Result<()> {
+        let Some(ref serving) = self.serving else {
+            return Ok(());
+        };
+        let (pos, len, first_id) = {
+            let ms = self.media_status();
+            let queue = self.queue.lock().unwrap();
+            let Some(pos) = ms.media.as_ref().and_then(|media| {
+                queue
+                    .iter()
+                    .position(|it| it.media.content_id == media.content_id)
+            }) else {
+                return Ok(());
+            };
+            // Status updates only list the items around the current one
+            let first_id = queue.first().and_then(|first| {
+                ms.items
+                    .iter()
+                    .flatten()
+                    .find(|it| it.media.content_id == first.media.content_id)
+                    .and_then(|it| it.item_id)
+            });
+            (pos, queue.len(), first_id)
+        };
+        if len - pos <= QUEUE_MARGIN {
+            let items = serving.load_pending(QUEUE_BATCH, QueueEnd::End)?;
+            if !items.is_empty() {
+                log::info!("Queueing {} more tracks", items.len());
+                self.insert_items(items, None).await?;
+            }
+        }
+        if let Some(first_id) = first_id.filter(|_| pos < QUEUE_MARGIN) {
+            let items = serving.load_pending(QUEUE_BATCH, QueueEnd::Start)?;
+            if !items.is_empty() {
+                log::info!("Queueing {} earlier tracks", items.len());
+                self.insert_items(items, Some(first_id)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The URL of a queue item, which identifies it across sessions
+    fn item_content_id(&self, item_id: i32) -> Option<String> {
+        let ms = self.media_status();
+        ms.items
+            .as_ref()?
+            .iter()
+            .find(|it| it.item_id == Some(item_id))
+            .map(|it| it.media.content_id.clone())
+    }
+
+    /// The local file being played, if we are serving it
+    fn current_track_path(&self) -> Option<PathBuf> {
+        let ms = self.media_status();
+        self.serving
+            .as_ref()?
+            .registry
+            .track_path(&ms.media.as_ref()?.content_id)
+    }
+
+    /// Save our position in the state file
+    fn checkpoint(&self, session_file: &SessionFile) {
+        let Some(track) = self.current_track_path() else {
+            return;
+        };
+        let position = self.media_status().current_time.unwrap_or_default();
+        if let Err(err) = session_file.save(&track, position) {
+            log::warn!("Could not save the session: {err:#}");
+        }
+    }
+
+    /// Ask the receiver for its status, for an up to date position
+    async fn refresh_media_status(&self) -> Result<(), rust_cast::errors::Error> {
+        let session = self.session();
+        let status = session
+            .device
+            .media
+            .get_status(&session.transport_id, Some(session.media_session_id))
+            .await?;
+        for ms in status.entries {
+            if ms.media_session_id == session.media_session_id {
+                self.set_media_status(ms);
+            }
+        }
+        Ok(())
+    }
+
+    /// The item following `item_id` in the queue, if we know of it
+    fn item_after(&self, item_id: i32) -> Option<i32> {
+        let ids = self.item_ids();
+        let pos = ids.iter().position(|&id| id == item_id)?;
+        ids.get(pos + 1).copied()
+    }
+
     async fn stop(&self) -> Result<(), rust_cast::errors::Error> {
-        let ms = self
-            .receiver
+        let session = self.session();
+        let ms = session
+            .device
             .media
-            .stop(&self.transport_id, self.media_session_id)
+            .stop(&session.transport_id, session.media_session_id)
             .await?;
         self.set_media_status(ms);
         Ok(())
     }
 
+    /// Reconnect after the connection dropped, retrying with backoff
+    async fn reconnect(&self) -> anyhow::Result<()> {
+        let mut delay = Duration::from_secs(1);
+        let mut attempt = 1;
+        loop {
+            tokio::time::sleep(delay).await;
+            match self.try_reconnect().await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < RECONNECT_ATTEMPTS => {
+                    log::warn!("Reconnection attempt {attempt} failed: {err:#}");
+                }
+                Err(err) => return Err(err),
+            }
+            attempt += 1;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    }
+
+    /// Rejoin our media session, or reload the queue where we were
+    async fn try_reconnect(&self) -> anyhow::Result<()> {
+        // The address may have changed, resolve again
+        let target = self.target.relocate().await?;
+        let device =
+            CastDevice::connect_without_host_verification(target.address, target.port).await?;
+        device
+            .connection
+            .connect(DEFAULT_DESTINATION_ID.to_string())
+            .await?;
+        let prev = self.session();
+        let receiver_status = device.receiver.get_status().await?;
+        if receiver_status
+            .applications
+            .iter()
+            .any(|app| app.transport_id == prev.transport_id)
+        {
+            device
+                .connection
+                .connect(prev.transport_id.as_str())
+                .await?;
+            let status = device.media.get_status(&prev.transport_id, None).await?;
+            if let Some(ms) = status
+                .entries
+                .into_iter()
+                .find(|ms| ms.media_session_id == prev.media_session_id)
+            {
+                log::info!("Rejoined media session {}", prev.media_session_id);
+                self.session.store(Arc::new(Session {
+                    device,
+                    transport_id: prev.transport_id.clone(),
+                    media_session_id: prev.media_session_id,
+                }));
+                self.set_media_status(ms);
+                self.set_receiver_status(receiver_status);
+                return Ok(());
+            }
+        }
+
+        // The session is gone (the receiver was restarted, or someone
+        // else cast to it), load the queue again
+        let items = self.queue.lock().unwrap().clone();
+        if items.is_empty() {
+            anyhow::bail!("The media session is gone");
+        }
+        // Not holding a guard across awaits
+        let last = self.media_status.load_full();
+        let start_index = last
+            .media
+            .as_ref()
+            .and_then(|media| {
+                items
+                    .iter()
+                    .position(|it| it.media.content_id == media.content_id)
+            })
+            .unwrap_or_default();
+        let app = device
+            .receiver
+            .launch_app(&CastDeviceApp::DefaultMediaReceiver)
+            .await?;
+        device.connection.connect(app.transport_id.as_str()).await?;
+        let media_queue = MediaQueue {
+            items,
+            start_index: start_index.try_into()?,
+            queue_type: QueueType::Playlist,
+            repeat_mode: last.repeat_mode.unwrap_or(RepeatMode::Off),
+        };
+        let mut status = device
+            .media
+            .load_queue(&app.transport_id, &app.session_id, &media_queue)
+            .await?;
+        let ms = status.entries.remove(0);
+        if let Some(current_time) = last.current_time {
+            device
+                .media
+                .seek(
+                    &app.transport_id,
+                    ms.media_session_id,
+                    Some(current_time),
+                    None,
+                    None,
+                )
+                .await?;
+        }
+        log::info!(
+            "Reloaded the queue at item {start_index}, media session {}",
+            ms.media_session_id
+        );
+        let receiver_status = device.receiver.get_status().await?;
+        self.session.store(Arc::new(Session {
+            device,
+            transport_id: app.transport_id,
+            media_session_id: ms.media_session_id,
+        }));
+        // We sought within the span already
+        *self.span_item_id.lock().unwrap() = ms.current_item_id;
+        // Don't merge with the items of the previous session
+        self.media_status.store(Arc::new(ms));
+        self.media_status_change.notify_one();
+        self.set_receiver_status(receiver_status);
+        Ok(())
+    }
+
     fn playback_status(&self) -> PlaybackStatus {
         let ms = self.media_status();
         match ms.player_state {
@@ -144,7 +615,7 @@ impl<'a> Player<'a> {
                     media_session_id,
                     ..
                 }) => {
-                    if media_session_id == Some(self.media_session_id) {
+                    if media_session_id == Some(self.session().media_session_id) {
                         PlaybackStatus::Playing
                     } else {
                         PlaybackStatus::Stopped
@@ -188,30 +659,126 @@ impl<'a> Player<'a> {
     }
 
     fn metadata(&self) -> mpris_server::Metadata {
-        // There is information loss going through the cast metadata format
-        // For multi-valued tags, we would be better off
-        // recognizing the URL and using metadata stored on this side.
         let ms = self.media_status();
-        let mut md1 = mpris_server::Metadata::new();
-        if let Some(ref media) = ms.media {
-            if let Some(MusicTrack(ref md0)) = media.metadata {
-                md1.set_album(md0.album_name.clone());
-                md1.set_title(md0.title.clone());
-                md1.set_album_artist(md0.album_artist.as_ref().map(|aa| vec![aa]));
-                md1.set_artist(md0.artist.as_ref().map(|a| vec![a]));
-                md1.set_composer(md0.composer.as_ref().map(|c| vec![c]));
-                md1.set_track_number(md0.track_number.map(|n| n.try_into().unwrap()));
-                md1.set_disc_number(md0.disc_number.map(|n| n.try_into().unwrap()));
-                md1.set_art_url(md0.images.first().map(|img| img.url.clone()));
-                md1.set_content_created(md0.release_date.clone());
+        let mut md1 = if let Some(ref media) = ms.media {
+            media_metadata(media, self.track_tags(media).as_ref())
+        } else {
+            mpris_server::Metadata::new()
+        };
+        md1.set_trackid(ms.current_item_id.map(mpris::item_track_id));
+        md1
+    }
+
+    /// Cast queue item ids, as far as the receiver told us
+    ///
+    /// This is the window of items around the current one
+    /// that media status updates include.
+    fn item_ids(&self) -> Vec<i32> {
+        let ms = self.media_status();
+        let Some(ref items) = ms.items else {
+            return Vec::new();
+        };
+        items.iter().filter_map(|it| it.item_id).collect()
+    }
+
+    /// Multi-valued tags, when we serve the track
+    fn track_tags(&self, media: &Media) -> Option<TagLists> {
+        self.serving
+            .as_ref()?
+            .registry
+            .track_tags(&media.content_id)
+    }
+
+    /// The part of the file a cue sheet track plays, when we serve it
+    fn track_span(&self, media: &Media) -> Option<cue::Span> {
+        self.serving
+            .as_ref()?
+            .registry
+            .track_span(&media.content_id)
+    }
+
+    /// Where the current track starts within its file
+    fn span_start(&self) -> f64 {
+        let ms = self.media_status();
+        ms.media
+            .as_ref()
+            .and_then(|media| self.track_span(media))
+            .map_or(0., |span| span.start)
+    }
+
+    /// Keep cue sheet tracks within their span of the file
+    ///
+    /// The receiver starts each one at the start of the file,
+    /// and would play on past its end.
+    async fn follow_span(&self) -> Result<(), rust_cast::errors::Error> {
+        let (item_id, span, time) = {
+            let ms = self.media_status();
+            if !matches!(
+                ms.player_state,
+                PlayerState::Playing | PlayerState::Buffering
+            ) {
+                return Ok(());
+            }
+            let Some(span) = ms.media.as_ref().and_then(|media| self.track_span(media)) else {
+                return Ok(());
+            };
+            (
+                ms.current_item_id,
+                span,
+                f64::from(ms.current_time.unwrap_or_default()),
+            )
+        };
+        let started = {
+            let mut span_item_id = self.span_item_id.lock().unwrap();
+            let started = *span_item_id != item_id;
+            *span_item_id = item_id;
+            started
+        };
+        if started && time < span.start {
+            let session = self.session();
+            session
+                .device
+                .media
+                .seek(
+                    &session.transport_id,
+                    session.media_session_id,
+                    Some(span.start as f32),
+                    None,
+                    None,
+                )
+                .await?;
+        } else if span.end.is_some_and(|end| time >= end - SPAN_END_SLACK) {
+            log::info!("Reached the end of a cue sheet track");
+            if self.can_go_next() {
+                self.next().await?;
+            } else {
+                self.stop().await?;
             }
-            md1.set_length(
-                media
-                    .duration
-                    .map(|d| mpris::cast_time_to_mpris_time(d.into())),
-            );
         }
-        md1
+        Ok(())
+    }
+
+    /// Time until the current cue sheet track ends, while playing
+    fn span_remaining(&self) -> Option<Duration> {
+        let ms = self.media_status();
+        if ms.player_state != PlayerState::Playing {
+            return None;
+        }
+        let end = self.track_span(ms.media.as_ref()?)?.end?;
+        let time = f64::from(ms.current_time?);
+        Some(Duration::from_secs_f64((end - time).max(0.)))
+    }
+
+    fn item_metadata(&self, item_id: i32) -> Option<mpris_server::Metadata> {
+        let ms = self.media_status();
+        let item = ms
+            .items
+            .as_ref()?
+            .iter()
+            .find(|it| it.item_id == Some(item_id))?;
+        let mut md = media_metadata(&item.media, self.track_tags(&item.media).as_ref());
+        md.set_trackid(Some(mpris::item_track_id(item_id)));
+        Some(md)
     }
 
     fn can_go_next(&self) -> bool {
@@ -270,12 +837,87 @@ impl<'a> Player<'a> {
     }
 }
 
+/// Queue item for a file served at `url`
+pub fn queue_item(ent: AudioFile, url: url::Url) -> QueueItem {
+    QueueItem {
+        media: Media {
+            content_id: url.into(),
+            stream_type: StreamType::Buffered,
+            content_type: ent.mime_type.to_owned(),
+            metadata: ent
+                .metadata
+                .map(|m| rust_cast::channels::media::Metadata::MusicTrack(m.cast_metadata)),
+            // Lets clients display the length before the receiver
+            // has buffered the track
+            duration: ent.duration.map(|d| d as f32),
+        },
+        item_id: None,
+    }
+}
+
+/// MPRIS metadata for a Cast media item
+///
+/// There is information loss going through the cast metadata format,
+/// `lists` has every value of multi-valued tags for the tracks we serve.
+fn media_metadata(media: &Media, lists: Option<&TagLists>) -> mpris_server::Metadata {
+    let mut md1 = mpris_server::Metadata::new();
+    if let Some(MusicTrack(ref md0)) = media.metadata {
+        md1.set_album(md0.album_name.clone());
+        md1.set_title(md0.title.clone());
+        if let Some(lists) = lists {
+            let some = |list: &Vec<String>| (!list.is_empty()).then(|| list.clone());
+            md1.set_album_artist(some(&lists.album_artists));
+            md1.set_artist(some(&lists.artists));
+            md1.set_composer(some(&lists.composers));
+            md1.set_genre(some(&lists.genres));
+        } else {
+            md1.set_album_artist(md0.album_artist.as_ref().map(|aa| vec![aa]));
+            md1.set_artist(md0.artist.as_ref().map(|a| vec![a]));
+            md1.set_composer(md0.composer.as_ref().map(|c| vec![c]));
+        }
+        md1.set_track_number(md0.track_number.map(|n| n.try_into().unwrap()));
+        md1.set_disc_number(md0.disc_number.map(|n| n.try_into().unwrap()));
+        md1.set_art_url(md0.images.first().map(|img| img.url.clone()));
+        md1.set_content_created(md0.release_date.clone());
+    }
+    md1.set_length(
+        media
+            .duration
+            .map(|d| mpris::cast_time_to_mpris_time(d.into())),
+    );
+    md1
+}
+
 /// Player main loop
 ///
 /// Read device messages, act on media status changes, and update player state
 /// until the receiver closes the connection or indicates it is done playing
-pub async fn run_player(server: &mpris_server::Server<Player<'static>>) {
+///
+/// With a session file, our position is saved periodically and on exit.
+pub async fn run_player(
+    server: &mpris_server::Server<Player<'static>>,
+    session_file: Option<&SessionFile>,
+) {
+    let finished = player_loop(server, session_file).await;
+    if let Some(session_file) = session_file {
+        if finished {
+            // Resuming would replay the end of the last track
+            if let Err(err) = session_file.clear() {
+                log::warn!("Could not clear the session: {err:#}");
+            }
+        } else {
+            server.imp().checkpoint(session_file);
+        }
+    }
+}
+
+/// Returns true when the queue played to its end
+async fn player_loop(
+    server: &mpris_server::Server<Player<'static>>,
+    session_file: Option<&SessionFile>,
+) -> bool {
     let player = server.imp();
+    let mut checkpoint = tokio::time::interval(CHECKPOINT_INTERVAL);
     let mut playback_status = player.playback_status();
     let mut loop_status = player.loop_status();
     let mut metadata = player.metadata();
@@ -283,9 +925,14 @@ pub async fn run_player(server: &mpris_server::Server<Player<'static>>) {
     let mut can_go_previous = player.can_go_previous();
     let mut volume = player.volume();
     let mut shuffle = player.shuffle_status();
+    let mut item_ids = player.item_ids();
+    // When the current cue sheet track should end
+    let mut span_end = None;
     // Volume is receiver status and needs a different notification
     //let mut volume = player.volume().await;
     loop {
+        // Replaced when reconnecting
+        let session = player.session();
         tokio::select! {
             _ = player.receiver_status_change.notified() => {
                 let p = player.volume();
@@ -294,7 +941,34 @@ pub async fn run_player(server: &mpris_server::Server<Player<'static>>) {
                     server.properties_changed([Property::Volume(p)]).await.unwrap();
                 }
             }
+            _ = tokio::time::sleep_until(span_end.unwrap_or_else(tokio::time::Instant::now)),
+                if span_end.is_some() => {
+                span_end = None;
+                // The answer brings us back below, to follow_span
+                if let Err(err) = player.refresh_media_status().await {
+                    log::warn!("Could not get the media status: {err}");
+                }
+            }
+            _ = checkpoint.tick(), if session_file.is_some() => {
+                if player.playback_status() == PlaybackStatus::Playing {
+                    if let Err(err) = player.refresh_media_status().await {
+                        log::warn!("Could not get the media status: {err}");
+                    }
+                }
+            }
             _ = player.media_status_change.notified() => {
+                if let Some(session_file) = session_file {
+                    player.checkpoint(session_file);
+                }
+                if let Err(err) = player.top_up().await {
+                    eprintln!("Not queueing more tracks: {err:#}");
+                }
+                if let Err(err) = player.follow_span().await {
+                    log::warn!("Could not keep to the cue sheet: {err}");
+                }
+                span_end = player
+                    .span_remaining()
+                    .map(|remaining| tokio::time::Instant::now() + remaining);
                 let mut props = Vec::new();
                 let p = player.playback_status();
                 if playback_status != p {
@@ -329,35 +1003,42 @@ pub async fn run_player(server: &mpris_server::Server<Player<'static>>) {
                 if !props.is_empty() {
                     server.properties_changed(props).await.unwrap();
                 }
+                let p = player.item_ids();
+                if item_ids != p {
+                    for signal in player.track_list_signals(&item_ids, &p) {
+                        server.track_list_signal(signal).await.unwrap();
+                    }
+                    item_ids = p;
+                }
             }
-            msg = player.receiver.receive() => {
+            msg = session.device.receive() => {
                 match msg {
                     Ok(ChannelMessage::Heartbeat(response)) => {
                         if matches!(response, HeartbeatResponse::Ping) {
-                            player.receiver.heartbeat.pong().await.unwrap();
+                            session.device.heartbeat.pong().await.unwrap();
                         }
                     }
                     Ok(ChannelMessage::Connection(response)) => {
                         log::debug!("[Connection] {:?}", response);
                         if matches!(response, ConnectionResponse::Close) {
-                            return;
+                            return false;
                         }
                     }
                     Ok(ChannelMessage::Media(response)) => {
                         log::debug!("[Media] {:?}", response);
                         if let MediaResponse::Status(stat) = response {
                             for ms in stat.entries {
-                                if ms.media_session_id != player.media_session_id {
+                                if ms.media_session_id != session.media_session_id {
                                     continue;
                                 }
                                 // The player became idle, and not because it hasn't started yet
                                 // Either it's Finished (ran out of playlist), or the user explicitly stopped it,
                                 // or some fatal error happened.  Either way, time to exit.
-                                if let Some(_reason) = ms.idle_reason {
+                                if let Some(ref reason) = ms.idle_reason {
                                     assert_eq!(ms.player_state, PlayerState::Idle);
                                     let Some(ref es) = ms.extended_status else {
                                         // Exit when at the end of the playlist
-                                        return;
+                                        return matches!(reason, IdleReason::Finished);
                                     };
                                     // At the moment the enum has just this element,
                                     // but match so any additions must be handled.
@@ -382,13 +1063,12 @@ pub async fn run_player(server: &mpris_server::Server<Player<'static>>) {
                     ),
                     Err(error) => {
                         log::error!("Error occurred while receiving message {}", error);
-                        player
-                            .receiver
-                            .connection
-                            .disconnect(DEFAULT_DESTINATION_ID)
-                            .await
-                            .unwrap();
-                        return;
+                        // Keep the MPRIS server (and our HTTP server) up,
+                        // and try to pick up where we were
+                        if let Err(err) = player.reconnect().await {
+                            log::error!("Could not reconnect: {err:#}");
+                            return false;
+                        }
                     }
                 }
             }
@@ -1,8 +1,10 @@
+use std::path::PathBuf;
+
 use mpris_server::zbus;
 use mpris_server::zbus::fdo;
 use mpris_server::{
     LoopStatus, Metadata, PlaybackRate, PlaybackStatus, PlayerInterface, RootInterface, Time,
-    TrackId, Volume,
+    TrackId, TrackListInterface, TrackListSignal, Uri, Volume,
 };
 use rust_cast::channels::media::RepeatMode;
 
@@ -12,6 +14,23 @@ fn errconvert(err: rust_cast::errors::Error) -> zbus::Error {
     zbus::Error::Failure(format!("rust_cast error {err}"))
 }
 
+fn enqueue_errconvert(err: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(format!("Could not enqueue: {err:#}"))
+}
+
+/// Only local files can be served
+fn uri_to_path(uri: &str) -> fdo::Result<PathBuf> {
+    let url = url::Url::parse(uri).map_err(|err| fdo::Error::InvalidArgs(err.to_string()))?;
+    if url.scheme() != "file" {
+        return Err(fdo::Error::NotSupported(format!(
+            "Unsupported URI scheme {}",
+            url.scheme()
+        )));
+    }
+    url.to_file_path()
+        .map_err(|()| fdo::Error::InvalidArgs(format!("Not a local path: {uri}")))
+}
+
 fn mpris_time_to_seek_time(time: Time) -> f32 {
     // No from or tryfrom in this case (lossy); "as" casts are the only option
     // mpris Time is internally i64 microseconds
@@ -22,6 +41,63 @@ pub fn cast_time_to_mpris_time(time: f64) -> Time {
     Time::from_micros((time * 1_000_000.) as i64)
 }
 
+// Track ids are D-Bus object paths, and must not be under /org/mpris
+const TRACK_ID_PREFIX: &str = "/com/github/g2p/joujou/item/";
+
+/// MPRIS track ids map to Cast queue item ids
+pub fn item_track_id(item_id: i32) -> TrackId {
+    // Negative ids don't happen, but '-' wouldn't be valid in a path
+    TrackId::try_from(format!("{TRACK_ID_PREFIX}{}", item_id.unsigned_abs())).unwrap()
+}
+
+fn track_id_item(track_id: &TrackId) -> fdo::Result<i32> {
+    track_id
+        .as_str()
+        .strip_prefix(TRACK_ID_PREFIX)
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| fdo::Error::InvalidArgs(format!("Unknown track {track_id}")))
+}
+
+/// Whether all of `short` appears within `long`, in the same order
+fn is_subsequence(short: &[i32], long: &[i32]) -> bool {
+    let mut long = long.iter();
+    short.iter().all(|id| long.any(|id1| id1 == id))
+}
+
+impl<'a> Player<'a> {
+    /// Describe a change of the known queue items with TrackList signals
+    pub(super) fn track_list_signals(&self, old: &[i32], new: &[i32]) -> Vec<TrackListSignal> {
+        if is_subsequence(old, new) {
+            new.iter()
+                .enumerate()
+                .filter(|(_, id)| !old.contains(id))
+                .map(|(pos, &id)| TrackListSignal::TrackAdded {
+                    metadata: self.item_metadata(id).unwrap_or_else(Metadata::new),
+                    after_track: pos
+                        .checked_sub(1)
+                        .map_or(TrackId::NO_TRACK, |prev| item_track_id(new[prev])),
+                })
+                .collect()
+        } else if is_subsequence(new, old) {
+            old.iter()
+                .filter(|id| !new.contains(id))
+                .map(|&id| TrackListSignal::TrackRemoved {
+                    track_id: item_track_id(id),
+                })
+                .collect()
+        } else {
+            let current_track = self
+                .media_status()
+                .current_item_id
+                .map_or(TrackId::NO_TRACK, item_track_id);
+            vec![TrackListSignal::TrackListReplaced {
+                tracks: new.iter().copied().map(item_track_id).collect(),
+                current_track,
+            }]
+        }
+    }
+}
+
 /// https://specifications.freedesktop.org/mpris-spec/latest/Media_Player.html
 impl<'a> RootInterface for Player<'a> {
     async fn can_raise(&self) -> fdo::Result<bool> {
@@ -54,7 +130,7 @@ impl<'a> RootInterface for Player<'a> {
     }
 
     async fn has_track_list(&self) -> fdo::Result<bool> {
-        Ok(false)
+        Ok(true)
     }
 
     async fn identity(&self) -> fdo::Result<String> {
@@ -67,15 +143,21 @@ impl<'a> RootInterface for Player<'a> {
     }
 
     async fn supported_uri_schemes(&self) -> fdo::Result<Vec<String>> {
-        // We don't support https://specifications.freedesktop.org/mpris-spec/latest/Player_Interface.html#Method:OpenUri
-        // so keep the list empty
-        Ok(Vec::new())
+        // For https://specifications.freedesktop.org/mpris-spec/latest/Player_Interface.html#Method:OpenUri
+        // we need to be serving files
+        if self.serving.is_none() {
+            return Ok(Vec::new());
+        }
+        Ok(vec!["file".to_owned()])
     }
 
     async fn supported_mime_types(&self) -> fdo::Result<Vec<String>> {
-        // We don't support https://specifications.freedesktop.org/mpris-spec/latest/Player_Interface.html#Method:OpenUri
-        // so keep the list empty
-        Ok(Vec::new())
+        if self.serving.is_none() {
+            return Ok(Vec::new());
+        }
+        Ok(crate::audio::supported_mime_types()
+            .map(str::to_owned)
+            .collect())
     }
 }
 
@@ -106,7 +188,7 @@ impl<'a> PlayerInterface for Player<'a> {
     }
 
     async fn stop(&self) -> fdo::Result<()> {
-        // TODO: kill self.media_session_id, exit task
+        // TODO: kill the media session, exit task
         self.stop().await.map_err(errconvert)?;
         Ok(())
     }
@@ -117,11 +199,13 @@ impl<'a> PlayerInterface for Player<'a> {
     }
 
     async fn seek(&self, offset: Time) -> fdo::Result<()> {
-        self.receiver
+        let session = self.session();
+        session
+            .device
             .media
             .seek(
-                &self.transport_id,
-                self.media_session_id,
+                &session.transport_id,
+                session.media_session_id,
                 None,
                 Some(mpris_time_to_seek_time(offset)),
                 None,
@@ -134,12 +218,16 @@ impl<'a> PlayerInterface for Player<'a> {
     async fn set_position(&self, track_id: TrackId, position: Time) -> fdo::Result<()> {
         // TODO check TrackId matches
         log::debug!("set_position TrackId {track_id}");
-        self.receiver
+        // Positions are within the track, for cue sheets too
+        let start = self.span_start() as f32;
+        let session = self.session();
+        session
+            .device
             .media
             .seek(
-                &self.transport_id,
-                self.media_session_id,
-                Some(mpris_time_to_seek_time(position)),
+                &session.transport_id,
+                session.media_session_id,
+                Some(start + mpris_time_to_seek_time(position)),
                 None,
                 None,
             )
@@ -148,11 +236,16 @@ impl<'a> PlayerInterface for Player<'a> {
         Ok(())
     }
 
-    async fn open_uri(&self, _uri: String) -> fdo::Result<()> {
-        // Is it possible to return something like NoSuchMethod?
-        Err(fdo::Error::NotSupported(
-            "Loading on the fly is not supported".to_owned(),
-        ))
+    async fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        // Play next: insert after the current item
+        let insert_before = self
+            .media_status()
+            .current_item_id
+            .and_then(|id| self.item_after(id));
+        self.enqueue(uri_to_path(&uri)?, insert_before)
+            .await
+            .map_err(enqueue_errconvert)?;
+        Ok(())
     }
 
     async fn playback_status(&self) -> fdo::Result<PlaybackStatus> {
@@ -164,12 +257,13 @@ impl<'a> PlayerInterface for Player<'a> {
     }
 
     async fn set_loop_status(&self, loop_status: LoopStatus) -> zbus::Result<()> {
-        let ms = self
-            .receiver
+        let session = self.session();
+        let ms = session
+            .device
             .media
             .update_queue(
-                &self.transport_id,
-                self.media_session_id,
+                &session.transport_id,
+                session.media_session_id,
                 Some(match loop_status {
                     LoopStatus::None => RepeatMode::Off,
                     LoopStatus::Track => RepeatMode::Single,
@@ -199,12 +293,13 @@ impl<'a> PlayerInterface for Player<'a> {
     async fn set_shuffle(&self, shuffle: bool) -> zbus::Result<()> {
         // Will setting this to false restore the original order?
         // Needs testing
-        let ms = self
-            .receiver
+        let session = self.session();
+        let ms = session
+            .device
             .media
             .update_queue(
-                &self.transport_id,
-                self.media_session_id,
+                &session.transport_id,
+                session.media_session_id,
                 None,
                 Some(shuffle),
             )
@@ -226,15 +321,17 @@ impl<'a> PlayerInterface for Player<'a> {
         // XXX channel::receiver::set_volume drops most of
         // the RECEIVER_STATUS reply to keep only part of
         // the volume struct.
-        let _volume = self
-            .receiver
+        let session = self.session();
+        let _volume = session
+            .device
             .receiver
             .set_volume(volume as f32)
             .await
             .map_err(errconvert)?;
         // So we follow up with a get_status call
         self.set_receiver_status(
-            self.receiver
+            session
+                .device
                 .receiver
                 .get_status()
                 .await
@@ -244,9 +341,10 @@ impl<'a> PlayerInterface for Player<'a> {
     }
 
     async fn position(&self) -> fdo::Result<Time> {
+        let start = self.span_start();
         let ms = self.media_status();
         Ok(cast_time_to_mpris_time(
-            ms.current_time.unwrap_or_default().into(),
+            f64::from(ms.current_time.unwrap_or_default()) - start,
         ))
     }
 
@@ -287,3 +385,68 @@ impl<'a> PlayerInterface for Player<'a> {
         Ok(true)
     }
 }
+
+/// https://specifications.freedesktop.org/mpris-spec/latest/Track_List_Interface.html
+impl<'a> TrackListInterface for Player<'a> {
+    async fn get_tracks_metadata(&self, track_ids: Vec<TrackId>) -> fdo::Result<Vec<Metadata>> {
+        // Unknown ids are skipped, as the spec allows
+        Ok(track_ids
+            .iter()
+            .filter_map(|track_id| self.item_metadata(track_id_item(track_id).ok()?))
+            .collect())
+    }
+
+    async fn add_track(
+        &self,
+        uri: Uri,
+        after_track: TrackId,
+        set_as_current: bool,
+    ) -> fdo::Result<()> {
+        let path = uri_to_path(&uri)?;
+        let insert_before = if after_track == TrackId::NO_TRACK {
+            // At the start of the list
+            self.item_ids().first().copied()
+        } else {
+            self.item_after(track_id_item(&after_track)?)
+        };
+        let item_id = self
+            .enqueue(path, insert_before)
+            .await
+            .map_err(enqueue_errconvert)?;
+        if set_as_current {
+            if let Some(item_id) = item_id {
+                self.jump(item_id).await.map_err(errconvert)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_track(&self, track_id: TrackId) -> fdo::Result<()> {
+        let item_id = track_id_item(&track_id)?;
+        self.remove_items(&[item_id]).await.map_err(errconvert)?;
+        Ok(())
+    }
+
+    async fn go_to(&self, track_id: TrackId) -> fdo::Result<()> {
+        let item_id = track_id_item(&track_id)?;
+        self.jump(item_id).await.map_err(errconvert)?;
+        Ok(())
+    }
+
+    async fn tracks(&self) -> fdo::Result<Vec<TrackId>> {
+        Ok(self.item_ids().into_iter().map(item_track_id).collect())
+    }
+
+    async fn can_edit_tracks(&self) -> fdo::Result<bool> {
+        // AddTrack needs us to be serving files
+        Ok(self.serving.is_some())
+    }
+}
+
+#[test]
+fn check_subsequence() {
+    assert!(is_subsequence(&[1, 3], &[1, 2, 3]));
+    assert!(is_subsequence(&[], &[1]));
+    assert!(!is_subsequence(&[3, 1], &[1, 2, 3]));
+    assert!(!is_subsequence(&[1, 4], &[1, 2, 3]));
+}
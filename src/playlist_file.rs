@@ -0,0 +1,296 @@
+//! Playlists saved by other players: M3U/M3U8, PLS and XSPF
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::audio::Metadata;
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl Format {
+    fn from_ext(ext: &str) -> Option<Self> {
+        match &*ext.to_ascii_lowercase() {
+            "m3u" | "m3u8" => Some(Self::M3u),
+            "pls" => Some(Self::Pls),
+            "xspf" => Some(Self::Xspf),
+            _ => None,
+        }
+    }
+}
+
+pub fn is_playlist_file(path: &Path) -> bool {
+    let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
+    Format::from_ext(ext).is_some()
+}
+
+/// Where an entry points
+#[derive(Debug)]
+pub enum Location {
+    Path(PathBuf),
+    // Streams and the like, which we don't play
+    Url(String),
+}
+
+/// What the playlist says about a track, for files without tags
+#[derive(Debug, Clone, Default)]
+pub struct TrackInfo {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+}
+
+impl TrackInfo {
+    /// Parse the "Artist - Title" of M3U and PLS titles
+    fn from_display_title(title: &str) -> Self {
+        let (artist, title) = match title.split_once(" - ") {
+            Some((artist, title)) => (Some(artist.trim().to_owned()), title),
+            None => (None, title),
+        };
+        Self {
+            title: Some(title.trim().to_owned()).filter(|title| !title.is_empty()),
+            artist,
+            ..Default::default()
+        }
+    }
+
+    /// Fill in what guessing from the file name gives
+    pub fn apply(&self, meta: &mut Metadata) {
+        let cmeta = &mut meta.cast_metadata;
+        if self.title.is_some() {
+            cmeta.title.clone_from(&self.title);
+        }
+        if let Some(ref artist) = self.artist {
+            cmeta.artist = Some(artist.clone());
+            meta.lists.artists = vec![artist.clone()];
+        }
+        if self.album.is_some() {
+            cmeta.album_name.clone_from(&self.album);
+        }
+        if self.track_number.is_some() {
+            cmeta.track_number = self.track_number;
+        }
+    }
+}
+
+pub struct Item {
+    pub location: Location,
+    pub info: TrackInfo,
+}
+
+/// Read the entries of a playlist file, in order
+///
+/// Relative entries are resolved against the directory of the playlist.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Item>> {
+    let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
+    let Some(format) = Format::from_ext(ext) else {
+        anyhow::bail!("Not a known playlist extension");
+    };
+    let path = std::path::absolute(path)?;
+    let text = decode(std::fs::read(&path)?);
+    let base = path.parent().unwrap_or(Path::new("/"));
+    Ok(match format {
+        Format::M3u => parse_m3u(&text, base),
+        Format::Pls => parse_pls(&text, base),
+        Format::Xspf => parse_xspf(&text, &path)?,
+    })
+}
+
+/// UTF-8, or Latin-1 as older M3U files are
+pub fn decode(data: Vec<u8>) -> String {
+    let text = String::from_utf8(data)
+        .unwrap_or_else(|err| err.into_bytes().into_iter().map(char::from).collect());
+    match text.strip_prefix('\u{feff}') {
+        Some(rest) => rest.to_owned(),
+        None => text,
+    }
+}
+
+/// A path, relative to `base` unless absolute, or a URL
+///
+/// Only `file:` and `scheme://` entries are URLs; names like
+/// "Live: Encore.flac" would parse as one too.
+fn location(base: &Path, entry: &str) -> Location {
+    if let Ok(url) = url::Url::parse(entry) {
+        if url.scheme() == "file" {
+            if let Ok(path) = url.to_file_path() {
+                return Location::Path(path);
+            }
+        } else if entry.contains("://") {
+            return Location::Url(entry.to_owned());
+        }
+    }
+    Location::Path(base.join(entry))
+}
+
+fn parse_m3u(text: &str, base: &Path) -> Vec<Item> {
+    let mut items = Vec::new();
+    // From the #EXTINF line, for the entry that follows
+    let mut info = TrackInfo::default();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            // Duration and attributes come before the comma
+            if let Some((_, title)) = extinf.split_once(',') {
+                info = TrackInfo::from_display_title(title);
+            }
+        } else if !line.is_empty() && !line.starts_with('#') {
+            items.push(Item {
+                location: location(base, line),
+                info: std::mem::take(&mut info),
+            });
+        }
+    }
+    items
+}
+
+fn parse_pls(text: &str, base: &Path) -> Vec<Item> {
+    // By entry number, PLS files don't have to list them in order
+    let mut entries: BTreeMap<u32, (Option<&str>, Option<&str>)> = BTreeMap::new();
+    for line in text.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        if let Some(n) = key.strip_prefix("file").and_then(|n| n.parse().ok()) {
+            entries.entry(n).or_default().0 = Some(value.trim());
+        } else if let Some(n) = key.strip_prefix("title").and_then(|n| n.parse().ok()) {
+            entries.entry(n).or_default().1 = Some(value.trim());
+        }
+    }
+    entries
+        .into_values()
+        .filter_map(|(file, title)| {
+            Some(Item {
+                location: location(base, file?),
+                info: title.map(TrackInfo::from_display_title).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// XSPF locations are URIs, relative ones are resolved against `path`
+fn parse_xspf(text: &str, path: &Path) -> anyhow::Result<Vec<Item>> {
+    let base = url::Url::from_file_path(path)
+        .map_err(|()| anyhow::anyhow!("Can't make a URL of {}", path.display()))?;
+    let doc = roxmltree::Document::parse(text)?;
+    Ok(doc
+        .descendants()
+        .filter(|node| node.has_tag_name("track"))
+        .filter_map(|track| {
+            let child = |name: &str| {
+                track
+                    .children()
+                    .find(|node| node.has_tag_name(name))
+                    .and_then(|node| node.text())
+                    .map(|text| text.trim().to_owned())
+            };
+            let location = child("location")?;
+            let location = match base.join(&location) {
+                Ok(url) if url.scheme() == "file" => url
+                    .to_file_path()
+                    .map_or(Location::Url(location), Location::Path),
+                _ => Location::Url(location),
+            };
+            Some(Item {
+                location,
+                info: TrackInfo {
+                    title: child("title"),
+                    artist: child("creator"),
+                    album: child("album"),
+                    track_number: child("trackNum").and_then(|n| n.parse().ok()),
+                },
+            })
+        })
+        .collect())
+}
+
+#[test]
+fn check_m3u() {
+    let text = "#EXTM3U\n\
+        #EXTINF:215,Artist - Some Title\n\
+        01 Track.flac\n\
+        \n\
+        /music/02 Track.mp3\n\
+        http://example.com/stream\n";
+    let items = parse_m3u(text, Path::new("/lists"));
+    assert_eq!(items.len(), 3);
+    assert!(
+        matches!(items[0].location, Location::Path(ref p) if p == Path::new("/lists/01 Track.flac"))
+    );
+    assert_eq!(items[0].info.artist.as_deref(), Some("Artist"));
+    assert_eq!(items[0].info.title.as_deref(), Some("Some Title"));
+    assert!(
+        matches!(items[1].location, Location::Path(ref p) if p == Path::new("/music/02 Track.mp3"))
+    );
+    assert!(items[1].info.title.is_none());
+    assert!(matches!(items[2].location, Location::Url(_)));
+}
+
+#[test]
+fn check_location() {
+    let base = Path::new("/lists");
+    for (entry, expected) in [
+        ("Live: Encore.flac", "/lists/Live: Encore.flac"),
+        ("C:music.flac", "/lists/C:music.flac"),
+        ("file:///music/a%20b.flac", "/music/a b.flac"),
+    ] {
+        assert!(
+            matches!(location(base, entry), Location::Path(ref p) if p == Path::new(expected)),
+            "{entry}"
+        );
+    }
+    assert!(matches!(
+        location(base, "https://example.com/stream"),
+        Location::Url(_)
+    ));
+}
+
+#[test]
+fn check_pls() {
+    let text = "[playlist]\n\
+        File2=b.flac\n\
+        File1=a.flac\n\
+        Title1=First\n\
+        NumberOfEntries=2\n";
+    let items = parse_pls(text, Path::new("/lists"));
+    assert_eq!(items.len(), 2);
+    assert!(matches!(items[0].location, Location::Path(ref p) if p == Path::new("/lists/a.flac")));
+    assert_eq!(items[0].info.title.as_deref(), Some("First"));
+}
+
+#[test]
+fn check_xspf() -> anyhow::Result<()> {
+    let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <playlist version="1" xmlns="http://xspf.org/ns/0/">
+          <trackList>
+            <track>
+              <location>Some%20Album/01.flac</location>
+              <title>Some Title</title>
+              <creator>Artist</creator>
+              <trackNum>1</trackNum>
+            </track>
+            <track><location>http://example.com/stream</location></track>
+            <track><title>No location</title></track>
+          </trackList>
+        </playlist>"#;
+    let items = parse_xspf(text, Path::new("/lists/list.xspf"))?;
+    assert_eq!(items.len(), 2);
+    assert!(
+        matches!(items[0].location, Location::Path(ref p) if p == Path::new("/lists/Some Album/01.flac"))
+    );
+    assert!(matches!(items[1].location, Location::Url(_)));
+    // Applies on its own, for files we can't guess anything from
+    let mut meta = Metadata::default();
+    items[0].info.apply(&mut meta);
+    assert_eq!(meta.cast_metadata.title.as_deref(), Some("Some Title"));
+    assert_eq!(meta.cast_metadata.track_number, Some(1));
+    assert_eq!(meta.lists.artists, ["Artist"]);
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{Shuffle, Sort};
+
+/// How the playlist was shuffled, so that resuming keeps the order
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Shuffled {
+    pub shuffle: Shuffle,
+    pub seed: u64,
+}
+
+impl Shuffled {
+    /// With a new random seed
+    pub fn new(shuffle: Shuffle) -> Self {
+        Self {
+            shuffle,
+            seed: fastrand::u64(..),
+        }
+    }
+}
+
+/// Where we are in a playlist, enough to pick up from there
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedSession {
+    /// As passed on the command line, made absolute
+    pub paths: Vec<PathBuf>,
+    /// Missing from sessions saved before the order was kept
+    #[serde(default)]
+    pub sort: Option<Sort>,
+    #[serde(default)]
+    pub shuffle: Option<Shuffled>,
+    pub track: PathBuf,
+    /// In seconds
+    pub position: f32,
+}
+
+/// Our directory within an XDG base directory, see
+/// https://specifications.freedesktop.org/basedir-spec/latest/
+///
+/// `var` is the variable naming it, `home_default` where it is
+/// within $HOME when the variable isn't set.
+pub fn xdg_dir(var: &str, home_default: &str) -> anyhow::Result<PathBuf> {
+    let base = match std::env::var_os(var) {
+        // Relative paths are invalid per the spec and must be ignored
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => {
+            let Some(home) = std::env::var_os("HOME") else {
+                anyhow::bail!("Neither {var} nor HOME are set");
+            };
+            Path::new(&home).join(home_default)
+        }
+    };
+    Ok(base.join("joujou"))
+}
+
+/// $XDG_STATE_HOME/joujou
+pub fn state_dir() -> anyhow::Result<PathBuf> {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// The state file of the last session
+pub struct SessionFile {
+    path: PathBuf,
+    paths: Vec<PathBuf>,
+    sort: Sort,
+    shuffle: Option<Shuffled>,
+}
+
+impl SessionFile {
+    pub fn new(
+        paths: &[impl AsRef<Path>],
+        sort: Sort,
+        shuffle: Option<Shuffled>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: state_dir()?.join("session.json"),
+            paths: paths
+                .iter()
+                .map(|path| std::path::absolute(path.as_ref()))
+                .collect::<Result<_, _>>()?,
+            sort,
+            shuffle,
+        })
+    }
+
+    pub fn save(&self, track: &Path, position: f32) -> anyhow::Result<()> {
+        let saved = SavedSession {
+            paths: self.paths.clone(),
+            sort: Some(self.sort),
+            shuffle: self.shuffle,
+            track: std::path::absolute(track)?,
+            position,
+        };
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Write then rename, so that a crash can't leave a truncated file
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&saved)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// Forget the session, when there is nothing left to resume
+    pub fn clear(&self) -> anyhow::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn load() -> anyhow::Result<SavedSession> {
+        let path = state_dir()?.join("session.json");
+        let data = std::fs::read(&path).map_err(|err| {
+            anyhow::anyhow!(
+                "Could not read {} ({err}), nothing to resume",
+                path.display()
+            )
+        })?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+}
@@ -1,9 +1,21 @@
 use std::cmp::{Ordering, Reverse};
+use std::collections::{hash_map, HashMap};
 use std::ffi::OsStr;
+use std::io::IsTerminal;
+use std::num::NonZeroUsize;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
-use crate::audio::AudioFile;
+use anyhow::Context;
+
+use crate::audio::{self, AudioFile, Metadata};
+use crate::cache::MetadataCache;
+use crate::cli::{Shuffle, Sort};
+use crate::cue::{self, CueSheet};
+use crate::playlist_file::{self, Location, TrackInfo};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum CoverKind {
@@ -28,24 +40,195 @@ impl CoverKind {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct CoverFile {
     pub path: PathBuf,
     pub mime_type: &'static str,
 }
 
-pub struct Playlist {
+/// A music file found by listing, not parsed yet
+#[derive(Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// For when there is no embedded art
     pub cover: Option<CoverFile>,
-    pub entries: Vec<AudioFile>,
+    /// From a playlist file, for when there are no tags
+    pub info: Option<TrackInfo>,
+}
+
+impl Entry {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            cover: None,
+            info: None,
+        }
+    }
+
+    /// Read tags and validate the stream, the slow part of scanning
+    ///
+    /// Untagged files get metadata from their name and playlist entry,
+    /// unless they have a cue sheet, which is preferred next to the file
+    /// rather than in its tags.
+    pub fn load(
+        &self,
+        beets_db: Option<&rusqlite::Connection>,
+        cache: Option<&MetadataCache>,
+    ) -> anyhow::Result<AudioFile> {
+        let mut af = AudioFile::load(self.path.clone(), beets_db, cache)?;
+        af.cover.clone_from(&self.cover);
+        let embedded = af.metadata.as_mut().and_then(|meta| meta.cue_sheet.take());
+        af.cue_sheet = match cue::find_external(&self.path) {
+            Some(cue_path) => CueSheet::read(&cue_path)
+                .map_err(|err| log::warn!("{}: {err:#}", cue_path.display()))
+                .ok(),
+            None => embedded.as_deref().map(CueSheet::parse),
+        };
+        if af.metadata.is_none() && af.cue_sheet.is_none() {
+            af.metadata = audio::filename_metadata(&self.path);
+            // Even for names we can't guess from, such as non-UTF-8 ones
+            if let Some(ref info) = self.info {
+                info.apply(af.metadata.get_or_insert_with(Metadata::default));
+            }
+        }
+        Ok(af)
+    }
 }
 
-/// List music files, sort them appropriately, build the queue/playlist
-pub fn dir_to_playlist(
-    path: &Path,
-    beets_db: Option<&rusqlite::Connection>,
+pub struct Playlist {
+    pub entries: Vec<Entry>,
+}
+
+/// A listed file that couldn't be loaded
+pub struct Failure {
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+/// List files that couldn't be loaded on stderr, as they get skipped
+pub fn report_failures(failures: &[Failure]) {
+    if failures.is_empty() {
+        return;
+    }
+    eprintln!(
+        "Skipping {} files that could not be loaded:",
+        failures.len()
+    );
+    for failure in failures {
+        eprintln!("  {}: {:#}", failure.path.display(), failure.error);
+    }
+}
+
+impl Playlist {
+    /// Shuffle tracks, or albums keeping their tracks in order
+    ///
+    /// Albums are told apart by their tags, which means loading
+    /// every file; untagged files are grouped by directory.
+    /// The same seed gives the same order for the same files.
+    pub fn shuffle(
+        &mut self,
+        shuffle: Shuffle,
+        seed: u64,
+        beets_db: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        match shuffle {
+            Shuffle::Tracks => rng.shuffle(&mut self.entries),
+            Shuffle::Albums => {
+                let loaded = load_entries(&self.entries, beets_db)?;
+                let mut albums: Vec<Vec<Entry>> = Vec::new();
+                let mut album_indices = HashMap::new();
+                for (ent, loaded) in self.entries.drain(..).zip(loaded) {
+                    let key = AlbumKey::new(&ent, loaded.ok().as_ref());
+                    let ai = *album_indices.entry(key).or_insert_with(|| {
+                        albums.push(Vec::new());
+                        albums.len() - 1
+                    });
+                    albums[ai].push(ent);
+                }
+                rng.shuffle(&mut albums);
+                self.entries = albums.into_iter().flatten().collect();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What makes tracks part of the same album, when shuffling albums
+#[derive(PartialEq, Eq, Hash)]
+enum AlbumKey {
+    Tagged {
+        album_artist: Option<String>,
+        album: String,
+    },
+    Directory(Option<PathBuf>),
+}
+
+impl AlbumKey {
+    fn new(ent: &Entry, loaded: Option<&AudioFile>) -> Self {
+        let cmeta = loaded
+            .and_then(|af| af.metadata.as_ref())
+            .map(|meta| &meta.cast_metadata);
+        if let Some(album) = cmeta.and_then(|cmeta| cmeta.album_name.clone()) {
+            Self::Tagged {
+                album_artist: cmeta.and_then(|cmeta| cmeta.album_artist.clone()),
+                album,
+            }
+        } else {
+            Self::Directory(ent.path.parent().map(Path::to_owned))
+        }
+    }
+}
+
+/// Build the playlist from command-line arguments
+///
+/// Directories are expanded in place (see `dir_to_playlist`),
+/// and their files put in `sort` order; playlist files (M3U, PLS, XSPF)
+/// are expanded in their own order; files are taken as-is.
+/// This only lists files, unless sorting by tags; they are loaded
+/// as they are needed, and those that fail to load are dealt with then.
+pub fn paths_to_playlist(
+    paths: &[impl AsRef<Path>],
+    sort: Sort,
+    beets_db: Option<&Path>,
 ) -> anyhow::Result<Playlist> {
     let mut entries = Vec::new();
-    let mut cover: Option<CoverFile> = None;
-    let mut coverscore = None;
+    for path in paths {
+        let path = path.as_ref();
+        if path.is_dir() {
+            let mut playlist = dir_to_playlist(path)?;
+            sort_entries(&mut playlist.entries, sort, beets_db)?;
+            entries.extend(playlist.entries);
+        } else if playlist_file::is_playlist_file(path) {
+            let items = playlist_file::read(path)
+                .with_context(|| format!("Reading playlist {}", path.display()))?;
+            for item in items {
+                // Reported, but the rest of the playlist still plays
+                match item.location {
+                    Location::Path(entry_path) if entry_path.is_file() => entries.push(Entry {
+                        info: Some(item.info),
+                        ..Entry::new(entry_path)
+                    }),
+                    Location::Path(entry_path) => {
+                        eprintln!("{}: missing {}", path.display(), entry_path.display())
+                    }
+                    Location::Url(url) => {
+                        eprintln!("{}: can't play {url}, not a file", path.display())
+                    }
+                }
+            }
+        } else {
+            entries.push(Entry::new(path.to_owned()));
+        }
+    }
+    Ok(Playlist { entries })
+}
+
+/// List music files and their covers, in file system order
+fn dir_to_playlist(path: &Path) -> anyhow::Result<Playlist> {
+    let mut entries = Vec::new();
+    // Best cover of each directory, with its score
+    let mut dir_covers: HashMap<PathBuf, (CoverFile, _)> = HashMap::new();
 
     for dent in walkdir::WalkDir::new(path)
         .same_file_system(true)
@@ -71,48 +254,231 @@ pub fn dir_to_playlist(
             let ext = ext.to_ascii_lowercase();
             let ext = ext.as_str();
             if let Some(ckind) = CoverKind::from_ext(ext) {
+                let Some(dir) = path.parent().map(Path::to_owned) else {
+                    continue;
+                };
+                let sc1 = cover_score(&path);
                 let cover1 = CoverFile {
-                    path: path.clone(),
+                    path,
                     mime_type: ckind.mime_type(),
                 };
-                if let Some(ref c0) = cover {
-                    let sc0 = coverscore.get_or_insert_with(|| cover_score(&c0.path));
-                    let sc1 = cover_score(&path);
-                    if sc1.cmp(sc0) == Ordering::Greater {
-                        log::info!(
-                            "Preferring cover {} to {}",
-                            path.display(),
-                            c0.path.display()
-                        );
-                        cover = Some(cover1);
-                        coverscore = Some(sc1);
+                match dir_covers.entry(dir) {
+                    hash_map::Entry::Occupied(mut occ) => {
+                        let (c0, sc0) = occ.get();
+                        if sc1.cmp(sc0) == Ordering::Greater {
+                            log::info!(
+                                "Preferring cover {} to {}",
+                                cover1.path.display(),
+                                c0.path.display()
+                            );
+                            occ.insert((cover1, sc1));
+                        }
+                    }
+                    hash_map::Entry::Vacant(vac) => {
+                        vac.insert((cover1, sc1));
                     }
-                } else {
-                    cover = Some(cover1);
                 }
-            } else if let Some(af) = AudioFile::load_if_supported(path, beets_db)? {
-                entries.push(af);
+            } else if audio::has_known_extension(&path) {
+                entries.push(Entry::new(path));
             }
         }
     }
-    entries.sort_by(|a, b| {
-        natord::compare(&a.path.to_string_lossy(), &b.path.to_string_lossy())
-            .then_with(|| a.path.cmp(&b.path))
-    });
-    Ok(Playlist { cover, entries })
+    // Resolve covers per track: the nearest directory with a cover wins,
+    // so that each album within the tree gets its own
+    for ent in entries.iter_mut() {
+        ent.cover = ent
+            .path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(path))
+            .find_map(|dir| dir_covers.get(dir))
+            .map(|(cover, _)| cover.clone());
+    }
+    Ok(Playlist { entries })
 }
 
-pub fn files_to_playlist(
-    paths: &[impl AsRef<Path>],
-    beets_db: Option<&rusqlite::Connection>,
-) -> anyhow::Result<Playlist> {
-    Ok(Playlist {
-        cover: None,
-        entries: paths
-            .iter()
-            .map(|path| AudioFile::load(path.as_ref().to_owned(), beets_db))
-            .collect::<Result<_, _>>()?,
-    })
+/// The best cover directly within `dir`, for files that weren't listed
+pub fn dir_cover(dir: &Path) -> Option<CoverFile> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|dent| {
+            let path = dent.ok()?.path();
+            if path.file_name()?.as_bytes().starts_with(b".") {
+                return None;
+            }
+            let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+            let ckind = CoverKind::from_ext(&ext)?;
+            Some(CoverFile {
+                path,
+                mime_type: ckind.mime_type(),
+            })
+        })
+        .max_by_key(|cover| cover_score(&cover.path))
+}
+
+// Files are mostly waited on, use more threads than CPUs
+const MAX_LOAD_THREADS: usize = 16;
+
+// Only show progress for slow loads, and not too often
+const PROGRESS_DELAY: Duration = Duration::from_secs(1);
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Load entries on a pool of threads, results are in the same order
+///
+/// Each thread opens its own connections to the beets library
+/// and the metadata cache.
+pub fn load_entries(
+    entries: &[Entry],
+    beets_db: Option<&Path>,
+) -> anyhow::Result<Vec<anyhow::Result<AudioFile>>> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .saturating_mul(2)
+        .min(MAX_LOAD_THREADS)
+        .min(entries.len());
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<anyhow::Result<AudioFile>>> =
+        entries.iter().map(|_| None).collect();
+    let mut progress = Progress::new(entries.len());
+    std::thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let (tx, next) = (tx.clone(), &next);
+                scope.spawn(move || -> anyhow::Result<()> {
+                    let beets_db = audio::open_beets_db(beets_db)?;
+                    // Slower without it, but it isn't required
+                    let cache = MetadataCache::open()
+                        .map_err(|err| log::warn!("Not using the metadata cache: {err:#}"))
+                        .ok();
+                    loop {
+                        let i = next.fetch_add(1, atomic::Ordering::Relaxed);
+                        let Some(ent) = entries.get(i) else {
+                            return Ok(());
+                        };
+                        let result = ent.load(beets_db.as_ref(), cache.as_ref());
+                        if tx.send((i, result)).is_err() {
+                            return Ok(());
+                        }
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        for (done, (i, result)) in rx.into_iter().enumerate() {
+            results[i] = Some(result);
+            progress.update(done + 1);
+        }
+        progress.finish();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+    Ok(results
+        .into_iter()
+        .map(|result| result.expect("Every entry was loaded"))
+        .collect())
+}
+
+/// A progress line on stderr, once loading takes a while
+struct Progress {
+    total: usize,
+    start: Instant,
+    shown: Option<Instant>,
+    enabled: bool,
+}
+
+impl Progress {
+    fn new(total: usize) -> Self {
+        Self {
+            total,
+            start: Instant::now(),
+            shown: None,
+            enabled: std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn update(&mut self, done: usize) {
+        if !self.enabled || self.start.elapsed() < PROGRESS_DELAY {
+            return;
+        }
+        if done < self.total && self.shown.is_some_and(|t| t.elapsed() < PROGRESS_INTERVAL) {
+            return;
+        }
+        eprint!("\rLoading files: {done}/{}", self.total);
+        self.shown = Some(Instant::now());
+    }
+
+    fn finish(&self) {
+        if self.shown.is_some() {
+            eprintln!();
+        }
+    }
+}
+
+/// Sort keys for `Sort::Tags`, in order of precedence
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct TagOrder {
+    album_artist: Option<String>,
+    date: Option<String>,
+    album: Option<String>,
+    disc: Option<u32>,
+    track: Option<u32>,
+}
+
+impl TagOrder {
+    fn new(af: &AudioFile) -> Option<Self> {
+        let meta = af.metadata.as_ref()?;
+        let cmeta = &meta.cast_metadata;
+        Some(Self {
+            // Most albums only have track artists
+            album_artist: cmeta.album_artist.clone().or_else(|| cmeta.artist.clone()),
+            date: cmeta.release_date.clone(),
+            album: cmeta.album_name.clone(),
+            disc: cmeta.disc_number,
+            track: cmeta.track_number,
+        })
+    }
+}
+
+/// Put the files of a directory in order, path order breaking ties
+fn sort_entries(
+    entries: &mut Vec<Entry>,
+    sort: Sort,
+    beets_db: Option<&Path>,
+) -> anyhow::Result<()> {
+    if matches!(sort, Sort::None) {
+        return Ok(());
+    }
+    entries.sort_by(|a, b| path_order(&a.path, &b.path));
+    match sort {
+        Sort::Path | Sort::None => (),
+        Sort::Mtime => entries.sort_by_cached_key(|ent| {
+            std::fs::metadata(&ent.path)
+                .and_then(|md| md.modified())
+                .ok()
+        }),
+        Sort::Tags => {
+            let loaded = load_entries(entries, beets_db)?;
+            let mut keyed: Vec<_> = loaded
+                .into_iter()
+                .map(|loaded| {
+                    let key = loaded.ok().as_ref().and_then(TagOrder::new);
+                    // Files we couldn't read go last
+                    (key.is_none(), key)
+                })
+                .zip(entries.drain(..))
+                .collect();
+            keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+            entries.extend(keyed.into_iter().map(|(_, ent)| ent));
+        }
+    }
+    Ok(())
+}
+
+/// Natural ordering, so that track 10 comes after track 9
+fn path_order(a: &Path, b: &Path) -> Ordering {
+    natord::compare(&a.to_string_lossy(), &b.to_string_lossy()).then_with(|| a.cmp(b))
 }
 
 fn cover_score(path: &Path) -> impl Ord {
@@ -128,3 +494,42 @@ fn cover_score(path: &Path) -> impl Ord {
     // Lowest possible score
     Reverse(usize::MAX)
 }
+
+#[test]
+fn check_album_covers() -> anyhow::Result<()> {
+    let root = std::env::temp_dir().join(format!("joujou-covers-{}", std::process::id()));
+    for file in [
+        "cover.jpg",
+        "A/cover.jpg",
+        "A/01.flac",
+        "B/folder.jpg",
+        "B/front.png",
+        "B/01.flac",
+        "C/01.flac",
+    ] {
+        let file = root.join(file);
+        std::fs::create_dir_all(file.parent().unwrap())?;
+        std::fs::File::create(file)?;
+    }
+    let playlist = dir_to_playlist(&root);
+    std::fs::remove_dir_all(&root)?;
+    let covers: Vec<_> = playlist?
+        .entries
+        .into_iter()
+        .map(|ent| {
+            let cover = ent.cover.map(|cover| cover.path);
+            (ent.path, cover)
+        })
+        .collect();
+    assert_eq!(covers.len(), 3);
+    for (path, cover) in covers {
+        let expected = match path.parent().and_then(Path::file_name) {
+            Some(dir) if dir == "A" => root.join("A/cover.jpg"),
+            Some(dir) if dir == "B" => root.join("B/front.png"),
+            // No cover of its own
+            _ => root.join("cover.jpg"),
+        };
+        assert_eq!(cover, Some(expected), "{}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! Scaled-down covers, for clients that don't need a full-size scan
+
+use std::io::Cursor;
+use std::path::Path;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+
+pub const MIME_TYPE: &str = "image/jpeg";
+
+/// Longest side of the thumbnails we offer, largest first
+pub const SIZES: [u32; 3] = [600, 300, 100];
+
+const JPEG_QUALITY: u8 = 85;
+
+/// Width and height, from the image header
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+pub fn file_dimensions(path: &Path) -> Option<(u32, u32)> {
+    image::image_dimensions(path).ok()
+}
+
+/// Dimensions after scaling down so that the longest side is `size`
+pub fn scaled((width, height): (u32, u32), size: u32) -> (u32, u32) {
+    let scale = |side: u32, longest: u32| {
+        let side =
+            (u64::from(side) * u64::from(size) + u64::from(longest) / 2) / u64::from(longest);
+        (side as u32).max(1)
+    };
+    if width >= height {
+        (size, scale(height, width))
+    } else {
+        (scale(width, height), size)
+    }
+}
+
+/// Decode, scale down and encode as JPEG
+pub fn make(data: &[u8], size: u32) -> anyhow::Result<Vec<u8>> {
+    let img = image::load_from_memory(data)?;
+    let img = img.resize(size, size, FilterType::Lanczos3);
+    let mut out = Vec::new();
+    // No alpha in JPEG
+    img.into_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?;
+    Ok(out)
+}
+
+#[test]
+fn check_scaled() {
+    assert_eq!(scaled((3000, 3000), 300), (300, 300));
+    assert_eq!(scaled((1200, 900), 600), (600, 450));
+    assert_eq!(scaled((900, 1200), 600), (450, 600));
+    assert_eq!(scaled((4000, 1), 100), (100, 1));
+}
@@ -0,0 +1,425 @@
+//! Decode formats the Chromecast can't play, serve them as WAV
+//!
+//! Symphonia has no encoders, so the output is plain PCM.
+//! The stream is seekable: byte offsets map to sample offsets,
+//! so the receiver can seek with range requests like for any file.
+//!
+//! Streams beyond the receiver's limits are brought within them:
+//! bit depth is truncated, extra channels are downmixed to stereo
+//! and high sample rates are divided by averaging neighbouring frames.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use symphonia::core::audio::{Channels, SampleBuffer};
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatReader, SeekMode, SeekTo};
+use symphonia::core::units::TimeBase;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::audio::{self, Capabilities};
+
+pub const MIME_TYPE: &str = "audio/wav";
+
+const HEADER_LEN: u64 = 44;
+
+// Decode rather than seek when the requested offset is this close
+const MAX_SKIP: u64 = 1 << 20;
+
+fn io_error(err: Error) -> io::Error {
+    match err {
+        Error::IoError(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// A WAV file, decoded on the fly as it is read
+pub struct WavStream {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: TimeBase,
+    // Output frames average this many decoded frames
+    decimation: u32,
+    // Output weights of each decoded channel
+    mix: Vec<Vec<f64>>,
+    // Decoded samples short of a decimation group, for the next packet
+    leftover: Vec<i32>,
+    sample_rate: u32,
+    // 2 or 3 bytes per sample
+    sample_bytes: u16,
+    channels: u16,
+    n_frames: u64,
+    header: [u8; HEADER_LEN as usize],
+    pos: u64,
+    // Decoded data, starting at buf_start within the data chunk
+    buf: Vec<u8>,
+    buf_start: u64,
+    eof: bool,
+}
+
+impl WavStream {
+    pub fn open(path: &Path, caps: &Capabilities) -> anyhow::Result<Self> {
+        let reader = audio::open_format(path)?;
+        let Some(track) = reader.default_track() else {
+            anyhow::bail!("No audio track");
+        };
+        let track_id = track.id;
+        let params = track.codec_params.clone();
+        let decoder = symphonia::default::get_codecs().make(&params, &DecoderOptions::default())?;
+        let (Some(in_rate), Some(in_channels), Some(in_frames)) =
+            (params.sample_rate, params.channels, params.n_frames)
+        else {
+            anyhow::bail!("Unknown sample rate, channels or length");
+        };
+        let Some(decimation) = caps.decimation(in_rate) else {
+            anyhow::bail!(
+                "Can't bring {in_rate} Hz within {} Hz",
+                caps.max_sample_rate
+            );
+        };
+        let mix = mix_weights(in_channels, caps.max_channels);
+        let Some(out_channels) = mix.first().map(Vec::len) else {
+            anyhow::bail!("No channels");
+        };
+        let sample_bytes =
+            if params.bits_per_sample.unwrap_or(16) > 16 && caps.max_bits_per_sample >= 24 {
+                3
+            } else {
+                2
+            };
+        let mut stream = Self {
+            track_id,
+            time_base: params
+                .time_base
+                .unwrap_or_else(|| TimeBase::new(1, in_rate)),
+            reader,
+            decoder,
+            decimation,
+            channels: out_channels.try_into()?,
+            mix,
+            leftover: Vec::new(),
+            sample_rate: in_rate / decimation,
+            sample_bytes,
+            n_frames: in_frames / u64::from(decimation),
+            header: [0; HEADER_LEN as usize],
+            pos: 0,
+            buf: Vec::new(),
+            buf_start: 0,
+            eof: false,
+        };
+        let data_len: u32 = stream.data_len().try_into()?;
+        stream.header = wav_header(
+            stream.channels,
+            stream.sample_rate,
+            sample_bytes * 8,
+            data_len,
+        );
+        Ok(stream)
+    }
+
+    fn block_align(&self) -> u64 {
+        u64::from(self.sample_bytes) * u64::from(self.channels)
+    }
+
+    fn data_len(&self) -> u64 {
+        self.n_frames * self.block_align()
+    }
+
+    /// Total length, headers included
+    pub fn size(&self) -> u64 {
+        HEADER_LEN + self.data_len()
+    }
+
+    /// Frame index of a timestamp, in decoded frames
+    fn frame_at(&self, ts: u64) -> u64 {
+        let in_rate = u64::from(self.sample_rate) * u64::from(self.decimation);
+        let time = self.time_base.calc_time(ts);
+        time.seconds * in_rate + (time.frac * in_rate as f64).round() as u64
+    }
+
+    fn seek_data(&mut self, offset: u64) -> io::Result<()> {
+        let frame = offset / self.block_align();
+        let seconds = frame / u64::from(self.sample_rate);
+        let frac = (frame % u64::from(self.sample_rate)) as f64 / f64::from(self.sample_rate);
+        self.reader
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: symphonia::core::units::Time::new(seconds, frac),
+                    track_id: Some(self.track_id),
+                },
+            )
+            .map_err(io_error)?;
+        self.decoder.reset();
+        self.buf.clear();
+        self.leftover.clear();
+        self.eof = false;
+        // Set properly by the next decoded packet
+        self.buf_start = 0;
+        Ok(())
+    }
+
+    /// Decode the next packet into buf, replacing its contents
+    fn decode_next(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match self.reader.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.eof = true;
+                    return Ok(());
+                }
+                Err(err) => return Err(io_error(err)),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(Error::DecodeError(err)) => {
+                    log::warn!("Skipping undecodable packet: {err}");
+                    continue;
+                }
+                Err(err) => return Err(io_error(err)),
+            };
+            let mut samples = SampleBuffer::<i32>::new(decoded.capacity() as u64, *decoded.spec());
+            samples.copy_interleaved_ref(decoded);
+            self.buf.clear();
+            let in_channels = self.mix.len();
+            let group_len = in_channels * self.decimation as usize;
+            // The end of the previous packet starts the first group
+            let leftover_frames = self.leftover.len() / in_channels;
+            self.leftover.extend_from_slice(samples.samples());
+            for group in self.leftover.chunks_exact(group_len) {
+                for out_chan in 0..usize::from(self.channels) {
+                    let mut acc = 0.;
+                    for frame in group.chunks_exact(in_channels) {
+                        for (sample, weights) in frame.iter().zip(self.mix.iter()) {
+                            acc += f64::from(*sample) * weights[out_chan];
+                        }
+                    }
+                    let sample = (acc / f64::from(self.decimation))
+                        .round()
+                        .clamp(f64::from(i32::MIN), f64::from(i32::MAX))
+                        as i32;
+                    let bytes = sample.to_le_bytes();
+                    // Keep the most significant bytes
+                    self.buf
+                        .extend_from_slice(&bytes[4 - usize::from(self.sample_bytes)..]);
+                }
+            }
+            let used = self.leftover.len() - self.leftover.len() % group_len;
+            self.leftover.drain(..used);
+            let first_frame = self
+                .frame_at(packet.ts)
+                .saturating_sub(leftover_frames as u64)
+                / u64::from(self.decimation);
+            self.buf_start = first_frame * self.block_align();
+            return Ok(());
+        }
+    }
+
+    fn read_data(&mut self, offset: u64, out: &mut [u8]) -> io::Result<usize> {
+        let data_len = self.data_len();
+        if offset >= data_len {
+            return Ok(0);
+        }
+        let want = out
+            .len()
+            .min((data_len - offset).try_into().unwrap_or(usize::MAX));
+        let buf_end = self.buf_start + self.buf.len() as u64;
+        // Seek rather than decode our way there
+        if offset < self.buf_start || offset >= buf_end + MAX_SKIP {
+            self.seek_data(offset)?;
+        }
+        loop {
+            let buf_end = self.buf_start + self.buf.len() as u64;
+            if offset < self.buf_start {
+                // A gap in timestamps, fill it with silence
+                let n = want.min((self.buf_start - offset).try_into().unwrap_or(usize::MAX));
+                out[..n].fill(0);
+                return Ok(n);
+            }
+            if offset < buf_end {
+                let start = (offset - self.buf_start) as usize;
+                let n = want.min(self.buf.len() - start);
+                out[..n].copy_from_slice(&self.buf[start..start + n]);
+                return Ok(n);
+            }
+            if self.eof {
+                // The declared length was an estimate, pad with silence
+                out[..want].fill(0);
+                return Ok(want);
+            }
+            self.decode_next()?;
+        }
+    }
+}
+
+impl Read for WavStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = if self.pos < HEADER_LEN {
+            let start = self.pos as usize;
+            let n = out.len().min(self.header.len() - start);
+            out[..n].copy_from_slice(&self.header[start..start + n]);
+            n
+        } else {
+            self.read_data(self.pos - HEADER_LEN, out)?
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for WavStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        let Some(pos) = pos else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the start",
+            ));
+        };
+        // Decoding catches up lazily, on the next read
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+/// How much each decoded channel contributes to each output channel
+///
+/// Streams within the channel limit are passed through,
+/// others are downmixed to stereo.
+fn mix_weights(channels: Channels, max_channels: usize) -> Vec<Vec<f64>> {
+    use std::f64::consts::FRAC_1_SQRT_2;
+    let count = channels.count();
+    if count <= max_channels {
+        return (0..count)
+            .map(|i| (0..count).map(|o| if i == o { 1. } else { 0. }).collect())
+            .collect();
+    }
+    let left = Channels::FRONT_LEFT_CENTRE | Channels::SIDE_LEFT | Channels::REAR_LEFT;
+    let right = Channels::FRONT_RIGHT_CENTRE | Channels::SIDE_RIGHT | Channels::REAR_RIGHT;
+    let centre = Channels::FRONT_CENTRE | Channels::REAR_CENTRE;
+    let lfe = Channels::LFE1 | Channels::LFE2;
+    // Interleaved in bit order
+    let mut weights: Vec<Vec<f64>> = (0..u32::BITS)
+        .filter_map(|bit| Channels::from_bits(1 << bit))
+        .filter(|chan| channels.contains(*chan))
+        .map(|chan| {
+            if chan == Channels::FRONT_LEFT {
+                vec![1., 0.]
+            } else if chan == Channels::FRONT_RIGHT {
+                vec![0., 1.]
+            } else if left.contains(chan) {
+                vec![FRAC_1_SQRT_2, 0.]
+            } else if right.contains(chan) {
+                vec![0., FRAC_1_SQRT_2]
+            } else if centre.contains(chan) {
+                vec![FRAC_1_SQRT_2, FRAC_1_SQRT_2]
+            } else if lfe.contains(chan) {
+                vec![0., 0.]
+            } else {
+                vec![0.5, 0.5]
+            }
+        })
+        .collect();
+    // Scale down so that nothing clips
+    for out_chan in 0..2 {
+        let sum: f64 = weights.iter().map(|w| w[out_chan]).sum();
+        if sum > 1. {
+            for w in weights.iter_mut() {
+                w[out_chan] /= sum;
+            }
+        }
+    }
+    weights
+}
+
+fn wav_header(channels: u16, sample_rate: u32, bits: u16, data_len: u32) -> [u8; 44] {
+    let block_align = channels * bits / 8;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(data_len.saturating_add(36)).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * u32::from(block_align)).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&bits.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header.try_into().unwrap()
+}
+
+/// Runs blocking reads and seeks in place, for decoders
+///
+/// Decoding a chunk is quick enough that this beats shipping
+/// every read to the blocking thread pool.
+pub struct BlockingIo<T>(pub T);
+
+impl<T: Read + Unpin> AsyncRead for BlockingIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let inner = &mut self.get_mut().0;
+        let n = tokio::task::block_in_place(|| inner.read(buf.initialize_unfilled()))?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Seek + Unpin> AsyncSeek for BlockingIo<T> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        self.get_mut().0.seek(position)?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(self.get_mut().0.stream_position())
+    }
+}
+
+#[test]
+fn check_wav_header() {
+    let header = wav_header(2, 44100, 16, 1000);
+    assert_eq!(&header[0..4], b"RIFF");
+    assert_eq!(u32::from_le_bytes(header[4..8].try_into().unwrap()), 1036);
+    assert_eq!(
+        u32::from_le_bytes(header[28..32].try_into().unwrap()),
+        176400
+    );
+    assert_eq!(u16::from_le_bytes(header[32..34].try_into().unwrap()), 4);
+    assert_eq!(u32::from_le_bytes(header[40..44].try_into().unwrap()), 1000);
+}
+
+#[test]
+fn check_downmix() {
+    let stereo = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+    assert_eq!(mix_weights(stereo, 2), vec![vec![1., 0.], vec![0., 1.]]);
+    let surround = stereo
+        | Channels::FRONT_CENTRE
+        | Channels::LFE1
+        | Channels::REAR_LEFT
+        | Channels::REAR_RIGHT;
+    let weights = mix_weights(surround, 2);
+    assert_eq!(weights.len(), 6);
+    for out_chan in 0..2 {
+        let sum: f64 = weights.iter().map(|w| w[out_chan]).sum();
+        assert!((sum - 1.).abs() < 1e-9);
+    }
+    // The LFE is dropped
+    assert_eq!(weights[3], vec![0., 0.]);
+}